@@ -31,6 +31,12 @@ class AnsiCmd extends Command {
     required: false,
   })
 
+  tabWidth = Option.String("--tab-width", {
+    description: "tab-width",
+    validator: isInteger,
+    required: false,
+  })
+
   font = Option.String("--font", {
     description: "font",
     required: false,
@@ -69,6 +75,7 @@ class AnsiCmd extends Command {
     const mode = getMode(this.mode)
     const format = this.format
     const width = this.width
+    const tabWidth = this.tabWidth
     const font = getFontUrl(this.font)
     const fontSize = this.fontSize
     const lengthAdjust = this.lengthAdjust
@@ -89,22 +96,23 @@ class AnsiCmd extends Command {
           darkBg,
           fontSize,
           lengthAdjust,
-          sourcemap
+          sourcemap,
+          tabWidth,
         )
         process.stdout.write(s)
         break
       }
       case "html": {
         process.stdout.write(
-          to_html(input, theme, width, font, mode, lightBg, darkBg, fontSize, sourcemap),
+          to_html(input, theme, width, font, mode, lightBg, darkBg, fontSize, sourcemap, tabWidth),
         )
         break
       }
       case "text": {
-        process.stdout.write(to_text(input, width))
+        process.stdout.write(to_text(input, width, tabWidth))
       }
       case "ans": {
-        process.stdout.write(to_ans(input, width,))
+        process.stdout.write(to_ans(input, width, tabWidth))
       }
     }
   }
@@ -1,5 +1,6 @@
+use ansi2::canvas::{Canvas, TAB_WIDTH};
 use ansi2::{css::Mode, theme::Theme};
-use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::prelude::{JsError, wasm_bindgen};
 
 #[wasm_bindgen]
 #[allow(clippy::too_many_arguments)]
@@ -14,15 +15,15 @@ pub fn to_svg(
     font_size: Option<usize>,
     length_adjust: Option<String>,
     sourcemap: Option<bool>,
-) -> String {
+    tab_width: Option<usize>,
+) -> Result<String, JsError> {
     let mode = mode.map(|m| match m {
         Mode::Dark => ansi2::css::Mode::Dark,
         Mode::Light => ansi2::css::Mode::Light,
     });
-    ansi2::svg::to_svg(
-        &s,
+    Ok(ansi2::svg::canvas_to_svg(
+        &canvas(&s, width, tab_width)?,
         Into::<ansi2::theme::Theme>::into(theme),
-        width,
         font,
         mode,
         light_bg,
@@ -30,7 +31,7 @@ pub fn to_svg(
         font_size,
         length_adjust,
         sourcemap.unwrap_or(false),
-    )
+    ))
 }
 
 #[wasm_bindgen]
@@ -45,30 +46,49 @@ pub fn to_html(
     dark_bg: Option<String>,
     font_size: Option<usize>,
     sourcemap: Option<bool>,
-) -> String {
+    tab_width: Option<usize>,
+) -> Result<String, JsError> {
     let mode = mode.map(|m| match m {
         Mode::Dark => ansi2::css::Mode::Dark,
         Mode::Light => ansi2::css::Mode::Light,
     });
-    ansi2::html::to_html(
-        &s,
+    Ok(ansi2::html::canvas_to_html(
+        &canvas(&s, width, tab_width)?,
         Into::<ansi2::theme::Theme>::into(theme),
-        width,
         font,
         mode,
         light_bg,
         dark_bg,
         font_size,
         sourcemap.unwrap_or(false),
-    )
+    ))
 }
 
 #[wasm_bindgen]
-pub fn to_text(s: String, width: Option<usize>) -> String {
-    ansi2::text::to_text(&s, width)
+pub fn to_text(
+    s: String,
+    width: Option<usize>,
+    tab_width: Option<usize>,
+) -> Result<String, JsError> {
+    Ok(ansi2::text::canvas_to_text(&canvas(&s, width, tab_width)?))
 }
 
 #[wasm_bindgen]
-pub fn to_ans(s: String, width: Option<usize>) -> String {
-    ansi2::ans::to_ans(&s, width)
+pub fn to_ans(
+    s: String,
+    width: Option<usize>,
+    tab_width: Option<usize>,
+) -> Result<String, JsError> {
+    Ok(ansi2::ans::canvas_to_ans(&canvas(&s, width, tab_width)?))
+}
+
+// input comes from the page, so it's bounded by `MAX_CELLS` and throws
+// instead of running out of memory
+fn canvas(s: &str, width: Option<usize>, tab_width: Option<usize>) -> Result<Canvas, JsError> {
+    Ok(Canvas::try_new_with_tabs(
+        s,
+        width,
+        0,
+        tab_width.unwrap_or(TAB_WIDTH),
+    )?)
 }
@@ -0,0 +1,75 @@
+//! Throughput on a large generated log, run with `cargo bench --bench throughput`.
+//! The size in MB can be set with `ANSI2_BENCH_MB`, the default is 20.
+
+use ansi2::{
+    canvas::{Canvas, CanvasStream},
+    html::to_html,
+    lex::Lexer,
+    text::to_text,
+    theme::Theme,
+};
+use std::hint::black_box;
+use std::time::Instant;
+
+fn build_log(size: usize) -> String {
+    let mut s = String::with_capacity(size + 256);
+    let mut i = 0usize;
+    while s.len() < size {
+        s.push_str(&format!(
+            "\x1b[2m{i:>8}\x1b[0m \x1b[1;32m   Compiling\x1b[0m crate-{i} v0.{}.0 \x1b[38;5;{}m(/build/src)\x1b[39m \x1b[48;2;30;30;{}mok\x1b[m 中文\n",
+            i % 10,
+            i % 256,
+            i % 256,
+        ));
+        i += 1;
+    }
+    s
+}
+
+fn bench<T>(name: &str, bytes: usize, f: impl FnOnce() -> T) {
+    let start = Instant::now();
+    black_box(f());
+    let secs = start.elapsed().as_secs_f64();
+    let mb = bytes as f64 / 1024.0 / 1024.0;
+    println!("{name:<16} {secs:>8.3}s {:>10.2} MB/s", mb / secs);
+}
+
+fn main() {
+    let mb: usize = std::env::var("ANSI2_BENCH_MB")
+        .ok()
+        .and_then(|i| i.parse().ok())
+        .unwrap_or(20);
+    let log = build_log(mb * 1024 * 1024);
+    let n = log.len();
+
+    bench("lex", n, || {
+        let mut lexer = Lexer::new();
+        let mut count = lexer.feed(log.as_bytes()).len();
+        count += lexer.finish().len();
+        count
+    });
+    bench("canvas", n, || Canvas::new(&log, None));
+    bench("canvas stream", n, || {
+        let mut canvas = CanvasStream::new(None);
+        let mut rows = 0;
+        for chunk in log.as_bytes().chunks(64 * 1024) {
+            canvas.feed(chunk).unwrap();
+            rows += canvas.take_rows().len();
+        }
+        rows + canvas.finish().unwrap().h
+    });
+    bench("text", n, || to_text(&log, None));
+    bench("html", n, || {
+        to_html(
+            &log,
+            Theme::Vscode,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+        )
+    });
+}
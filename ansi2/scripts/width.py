@@ -0,0 +1,141 @@
+#!/usr/bin/env python3
+"""Generates the tables of src/width/tables.rs from a fixed version of the
+Unicode character database, `just width` runs it:
+
+    python3 ansi2/scripts/width.py > ansi2/src/width/tables.rs
+
+The files are downloaded to target/ucd/<version> the first time, or read
+from the directory given as the first argument, laid out like the ucd
+directory of unicode.org.
+"""
+
+import os
+import re
+import sys
+import urllib.request
+
+UCD_VERSION = "17.0.0"
+
+EAST_ASIAN_WIDTH = "EastAsianWidth.txt"
+GRAPHEME_BREAK = "auxiliary/GraphemeBreakProperty.txt"
+EMOJI_DATA = "emoji/emoji-data.txt"
+
+# controls that terminals handle themselves or show, they aren't zero width
+VISIBLE_CONTROL = {0x00AD, 0x2028, 0x2029}
+
+LINE = re.compile(r"([0-9A-F]+)(?:\.\.([0-9A-F]+))?\s*;\s*(\w+)")
+
+
+def ucd_file(path):
+    if len(sys.argv) > 1:
+        return os.path.join(sys.argv[1], path)
+    root = os.path.join(os.path.dirname(__file__), "..", "..", "target", "ucd")
+    local = os.path.join(root, UCD_VERSION, path)
+    if not os.path.exists(local):
+        os.makedirs(os.path.dirname(local), exist_ok=True)
+        url = f"https://www.unicode.org/Public/{UCD_VERSION}/ucd/{path}"
+        urllib.request.urlretrieve(url, local)
+    return local
+
+
+def entries(path):
+    """The code points listed in the file with their value, in order."""
+    with open(ucd_file(path), encoding="utf-8") as f:
+        lines = f.read().splitlines()
+    for line in lines:
+        m = LINE.match(line.split("#")[0].strip())
+        if m:
+            start = int(m.group(1), 16)
+            end = int(m.group(2) or m.group(1), 16)
+            for cp in range(start, end + 1):
+                yield cp, m.group(3)
+
+
+def properties(path):
+    """Maps each code point to its value, the `@missing` lines give the value
+    of the code points that aren't listed."""
+    values = {}
+    with open(ucd_file(path), encoding="utf-8") as f:
+        for line in f:
+            m = LINE.match(line.removeprefix("# @missing:").strip())
+            if line.startswith("# @missing:") and m:
+                start = int(m.group(1), 16)
+                end = int(m.group(2) or m.group(1), 16)
+                values.update(dict.fromkeys(range(start, end + 1), m.group(3)))
+    values.update(entries(path))
+    return values
+
+
+def table(name, doc, cps):
+    ranges = []
+    for cp in sorted(cps):
+        if ranges and ranges[-1][1] == cp - 1:
+            ranges[-1][1] = cp
+        else:
+            ranges.append([cp, cp])
+    print()
+    for line in doc:
+        print(f"// {line}")
+    print(f"pub(super) const {name}: &[(u32, u32)] = &[")
+    for start, end in ranges:
+        print(f"    (0x{start:04X}, 0x{end:04X}),")
+    print("];")
+
+
+def with_value(values, *wanted):
+    return [cp for cp, value in values.items() if value in wanted]
+
+
+east_asian_width = properties(EAST_ASIAN_WIDTH)
+grapheme_break = properties(GRAPHEME_BREAK)
+
+print(f"// Generated by scripts/width.py from the Unicode {UCD_VERSION} character")
+print("// database, don't edit by hand.")
+table(
+    "WIDE",
+    [
+        "East Asian Wide and Fullwidth characters, which include the emoji that",
+        "are presented as emoji by default, they take two cells in a terminal",
+    ],
+    with_value(east_asian_width, "W", "F"),
+)
+table(
+    "EXTEND",
+    [
+        "chars that never start a grapheme cluster of their own: combining and",
+        "spacing marks, joiners, variation selectors, emoji modifiers and tags",
+    ],
+    with_value(grapheme_break, "Extend", "SpacingMark", "ZWJ"),
+)
+table(
+    "ZERO_WIDTH",
+    [
+        "invisible format chars like the zero width space and the bidi controls,",
+        "they take no cell",
+    ],
+    [
+        cp
+        for cp in with_value(grapheme_break, "Control")
+        if cp >= 0xA0 and cp not in VISIBLE_CONTROL and not 0xD800 <= cp <= 0xDFFF
+    ],
+)
+table(
+    "PICTOGRAPHIC",
+    ["emoji and the symbols that can become one, a ZWJ joins them"],
+    [cp for cp, value in entries(EMOJI_DATA) if value == "Extended_Pictographic"],
+)
+table(
+    "HANGUL_L",
+    ["leading consonants of a Hangul syllable"],
+    with_value(grapheme_break, "L"),
+)
+table(
+    "HANGUL_V",
+    ["vowels of a Hangul syllable"],
+    with_value(grapheme_break, "V"),
+)
+table(
+    "HANGUL_T",
+    ["trailing consonants of a Hangul syllable"],
+    with_value(grapheme_break, "T"),
+)
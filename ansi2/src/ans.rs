@@ -3,7 +3,10 @@ use std::collections::BinaryHeap;
 use crate::{
     canvas::{Canvas, pixels_to_ans},
     color::AnsiColor,
+    error::Error,
+    lex::Sgr,
     node::Node,
+    theme::Palette,
 };
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct Item {
@@ -35,16 +38,18 @@ enum Step {
     Dim,
     Italic,
     Underline,
-    Hide,
+    Conceal,
     Strike,
+    Reverse,
 
     UnBold,
     UnBlink,
     UnDim,
     UnItalic,
     UnUnderline,
-    UnHide,
+    Reveal,
     UnStrike,
+    UnReverse,
     List(Vec<Step>),
 }
 
@@ -70,15 +75,17 @@ impl Step {
             Step::Dim => "2".to_string(),
             Step::Italic => "3".to_string(),
             Step::Underline => "4".to_string(),
-            Step::Hide => "8".to_string(),
+            Step::Conceal => "8".to_string(),
             Step::Strike => "9".to_string(),
+            Step::Reverse => "7".to_string(),
             Step::UnBold => "22".to_string(),
             Step::UnBlink => "25".to_string(),
             Step::UnDim => "22".to_string(),
             Step::UnItalic => "23".to_string(),
             Step::UnUnderline => "24".to_string(),
-            Step::UnHide => "28".to_string(),
+            Step::Reveal => "28".to_string(),
             Step::UnStrike => "29".to_string(),
+            Step::UnReverse => "27".to_string(),
             Step::List(vec) => vec.iter().map(|i| i.to_ans()).collect::<Vec<_>>().join(";"),
         }
     }
@@ -97,8 +104,9 @@ impl Step {
             Step::Dim => new_node.dim = true,
             Step::Underline => new_node.underline = true,
             Step::Italic => new_node.italic = true,
-            Step::Hide => new_node.hide = true,
+            Step::Conceal => new_node.conceal = true,
             Step::Strike => new_node.strike = true,
+            Step::Reverse => new_node.reverse = true,
             Step::UnBold | Step::UnDim => {
                 new_node.dim = false;
                 new_node.bold = false
@@ -106,8 +114,9 @@ impl Step {
             Step::UnBlink => new_node.blink = false,
             Step::UnUnderline => new_node.underline = false,
             Step::UnItalic => new_node.italic = false,
-            Step::UnHide => new_node.hide = false,
+            Step::Reveal => new_node.conceal = false,
             Step::UnStrike => new_node.strike = false,
+            Step::UnReverse => new_node.reverse = false,
 
             Step::List(vec) => {
                 for i in vec {
@@ -147,8 +156,12 @@ fn bfs(from: &Node, to: &Node) -> Vec<Step> {
         if top.state.dim != to.dim {
             ctrl.push(if to.dim { Step::Dim } else { Step::UnDim });
         }
-        if top.state.hide != to.hide {
-            ctrl.push(if to.hide { Step::Hide } else { Step::UnHide });
+        if top.state.conceal != to.conceal {
+            ctrl.push(if to.conceal {
+                Step::Conceal
+            } else {
+                Step::Reveal
+            });
         }
 
         if top.state.strike != to.strike {
@@ -159,6 +172,14 @@ fn bfs(from: &Node, to: &Node) -> Vec<Step> {
             });
         }
 
+        if top.state.reverse != to.reverse {
+            ctrl.push(if to.reverse {
+                Step::Reverse
+            } else {
+                Step::UnReverse
+            });
+        }
+
         if top.state.italic != to.italic {
             ctrl.push(if to.italic {
                 Step::Italic
@@ -173,8 +194,12 @@ fn bfs(from: &Node, to: &Node) -> Vec<Step> {
                 Step::UnUnderline
             });
         }
-        if top.state.hide != to.hide {
-            ctrl.push(if to.hide { Step::Hide } else { Step::UnHide });
+        if top.state.conceal != to.conceal {
+            ctrl.push(if to.conceal {
+                Step::Conceal
+            } else {
+                Step::Reveal
+            });
         }
 
         let mut sgr1: Vec<Step> = vec![];
@@ -211,12 +236,7 @@ fn bfs(from: &Node, to: &Node) -> Vec<Step> {
             }
         }
 
-        for i in ctrl
-            .into_iter()
-            .chain(sgr1.into_iter())
-            .chain(sgr2.into_iter())
-            .chain(sgr3.into_iter())
-        {
+        for i in ctrl.into_iter().chain(sgr1).chain(sgr2).chain(sgr3) {
             if top.steps.contains(&i) {
                 continue;
             }
@@ -249,12 +269,29 @@ pub fn min_distance(from: &Node, to: &Node) -> String {
         .collect()
 }
 
+// the redefined colors are written first, they apply to the whole canvas
+fn palette_to_ans(palette: &Palette) -> String {
+    let colors = palette
+        .colors
+        .iter()
+        .map(|(&n, &rgb)| Sgr::SetPalette(n, rgb));
+    let fg = palette.foreground.map(Sgr::SetDefaultForeground);
+    let bg = palette.background.map(Sgr::SetDefaultBackground);
+    colors.chain(fg).chain(bg).map(|i| i.to_ans()).collect()
+}
+
 pub fn to_ans<S: AsRef<str>>(str: S, width: Option<usize>) -> String {
-    let s = str.as_ref();
-    let canvas = Canvas::new(s, width);
+    canvas_to_ans(&Canvas::new(str, width))
+}
+
+/// Like [`to_ans`], but fails on input that would need a huge canvas.
+pub fn try_to_ans<S: AsRef<str>>(str: S, width: Option<usize>) -> Result<String, Error> {
+    Ok(canvas_to_ans(&Canvas::try_new(str, width)?))
+}
 
-    let pixels = canvas.minify();
-    pixels_to_ans(pixels)
+/// Renders a canvas, e.g. one cropped with [`Canvas::view`].
+pub fn canvas_to_ans(canvas: &Canvas) -> String {
+    palette_to_ans(&canvas.palette) + &pixels_to_ans(canvas.minify())
 }
 
 #[cfg(test)]
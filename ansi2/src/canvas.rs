@@ -1,17 +1,130 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeSet, HashMap};
 
 use crate::{
     ans::min_distance,
-    color::{AnsiColor, Color8},
-    lex::{Sgr, Token, parse_ansi},
+    color::AnsiColor,
+    error::Error,
+    lex::{Charset, DecMode, Lexer, PromptMark, Sgr, Token, char_tokens},
     node::Node,
+    theme::Palette,
+    width::{cluster_width, extends, graphemes, str_width},
 };
 
+/// Upper bound on `w * h` of the canvases built by [`Canvas::try_new`] and
+/// held by [`CanvasStream`].
+pub const MAX_CELLS: usize = 1 << 24;
+
+/// Columns between the tab stops a canvas starts with.
+pub const TAB_WIDTH: usize = 8;
+
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Canvas {
     pub pixels: Vec<Vec<Node>>,
     pub w: usize,
     pub h: usize,
+    /// Colors redefined by the input, renderers apply them over the theme.
+    pub palette: Palette,
+    /// `OSC 133` marks in the order they appear in the input.
+    pub marks: Vec<Mark>,
+    /// Rows scrolled off the top of the screen, 0 unless the canvas is built
+    /// with [`Canvas::new_screen`].
+    pub scrollback: usize,
+    /// The last frame of the alternate screen, if the input switched to it.
+    pub alternate: Option<Box<Canvas>>,
+}
+
+/// The rows of a canvas to render, see [`Canvas::view`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum View {
+    /// The screen at the end of the input.
+    Screen,
+    /// The scrollback followed by the screen.
+    All,
+    /// The last `n` rows.
+    Last(usize),
+}
+
+/// An `OSC 133` mark at the cursor position it was received at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mark {
+    pub x: usize,
+    pub y: usize,
+    pub kind: PromptMark,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneKind {
+    Prompt,
+    Command,
+    Output,
+}
+
+/// The cells between two `OSC 133` marks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Zone {
+    pub kind: ZoneKind,
+    /// Index of the command the zone belongs to, a command is everything
+    /// from its prompt to its `D` mark.
+    pub command: usize,
+    /// (row, column) of the first cell.
+    pub start: (usize, usize),
+    /// (row, column) after the last cell.
+    pub end: (usize, usize),
+    pub exit: Option<i32>,
+}
+
+// ends the open zone at `end`, empty zones are dropped
+fn close_zone(v: &mut Vec<Zone>, open: Option<Zone>, end: (usize, usize)) {
+    if let Some(zone) = open
+        && zone.start < end
+    {
+        v.push(Zone { end, ..zone });
+    }
+}
+
+// the last zone ends at row `h` if no mark closes it
+fn zones(marks: &[Mark], h: usize) -> Vec<Zone> {
+    let mut v = Vec::new();
+    let mut open: Option<Zone> = None;
+    let mut command: Option<usize> = None;
+    let mut next = 0;
+
+    for mark in marks {
+        let pos = (mark.y, mark.x);
+        let kind = match mark.kind {
+            PromptMark::PromptStart => ZoneKind::Prompt,
+            PromptMark::CommandStart => ZoneKind::Command,
+            PromptMark::OutputStart => ZoneKind::Output,
+            PromptMark::CommandEnd(exit) => {
+                close_zone(&mut v, open.take(), pos);
+                if let Some(c) = command.take() {
+                    for zone in v.iter_mut().rev().take_while(|z| z.command == c) {
+                        zone.exit = exit;
+                    }
+                }
+                continue;
+            }
+            PromptMark::Other(_) => continue,
+        };
+        close_zone(&mut v, open.take(), pos);
+        // a prompt always starts a new command
+        if kind == ZoneKind::Prompt {
+            command = None;
+        }
+        let command = *command.get_or_insert_with(|| {
+            next += 1;
+            next - 1
+        });
+        open = Some(Zone {
+            kind,
+            command,
+            start: pos,
+            end: pos,
+            exit: None,
+        });
+    }
+    close_zone(&mut v, open, (h, 0));
+    v
 }
 
 fn set_node(v: &mut Vec<Vec<Node>>, node: Node, x: usize, y: usize) {
@@ -19,38 +132,43 @@ fn set_node(v: &mut Vec<Vec<Node>>, node: Node, x: usize, y: usize) {
     v[y][x] = node;
 }
 
+// all rows have the same length, so only the new rows at the bottom need
+// padding unless w grows
 fn ensure_shape(v: &mut Vec<Vec<Node>>, w: usize, h: usize) {
     while v.len() <= h {
         v.push(Vec::new());
     }
 
-    for row in v {
-        while row.len() <= w {
-            let empty = Node {
-                bg_color: AnsiColor::Default,
-                color: AnsiColor::Default,
-                bold: false,
-                text: ' '.into(),
-                blink: false,
-                dim: false,
-                italic: false,
-                underline: false,
-                hide: false,
-                strike: false,
-
-                bg_color_r: (0, 0),
-                color_r: (0, 0),
-                bold_r: (0, 0),
-                blink_r: (0, 0),
-                text_r: (0, 0),
-                dim_r: (0, 0),
-                italic_r: (0, 0),
-                underline_r: (0, 0),
-                hide_r: (0, 0),
-                strike_r: (0, 0),
-            };
-            row.push(empty);
+    let len = v[0].len().max(w + 1);
+    if v[v.len() - 1].len() >= len {
+        return;
+    }
+    let empty = empty_node();
+    for row in v.iter_mut().rev() {
+        if row.len() >= len {
+            break;
         }
+        row.resize(len, empty.clone());
+    }
+}
+
+// blanks the other half of the wide chars that cells `x..x + width` cut
+fn split_wide(row: &mut [Node], x: usize, width: usize) {
+    if x > 0 && row[x].is_continuation() {
+        row[x - 1].text = ' '.into();
+    }
+    if let Some(next) = row.get_mut(x + width)
+        && next.is_continuation()
+    {
+        next.text = ' '.into();
+    }
+}
+
+// the cell of a row that was never written
+fn empty_node() -> Node {
+    Node {
+        text: ' '.into(),
+        ..Node::default()
     }
 }
 
@@ -68,408 +186,1298 @@ fn offset_range(range: (usize, usize), offset: (usize, usize)) -> (usize, usize)
     (a1 + b1, a1 + b2)
 }
 
-fn erase(pixels: &mut [Vec<Node>], start_x: usize, start_y: usize, end_x: usize, end_y: usize) {
-    for row in pixels.iter_mut().skip(start_y).take(end_y - start_y) {
-        for cell in row.iter_mut().skip(start_x).take(end_x - start_x) {
-            *cell = Default::default();
+// blanks the cells from (start_x, start_y) up to but not including
+// (end_x, end_y) in reading order, rows in between are erased whole
+fn erase(pixels: &mut [Vec<Node>], start: (usize, usize), end: (usize, usize), blank: &Node) {
+    let (start_x, start_y) = start;
+    let (end_x, end_y) = end;
+    for (y, row) in pixels.iter_mut().enumerate().skip(start_y) {
+        if y > end_y {
+            break;
+        }
+        let from = if y == start_y { start_x } else { 0 };
+        let to = if y == end_y { end_x } else { row.len() }.min(row.len());
+        if from < to {
+            split_wide(row, from, to - from);
+        }
+        for cell in row.iter_mut().take(to).skip(from) {
+            *cell = blank.clone();
+        }
+    }
+}
+
+// blanks both halves of a wide char that the edge before cell `x` cuts
+fn cut_wide(row: &mut [Node], x: usize) {
+    if x > 0 && row.get(x).is_some_and(Node::is_continuation) {
+        row[x - 1].text = ' '.into();
+        row[x].text = ' '.into();
+    }
+}
+
+// moves the cells of `row` from `x` on right by `n`, cells moved past
+// `end` are lost
+fn insert_cells(row: &mut [Node], x: usize, end: usize, n: usize, blank: &Node) {
+    let end = end.min(row.len());
+    if x < end {
+        cut_wide(row, x);
+        cut_wide(row, end);
+        let cells = &mut row[x..end];
+        let n = n.min(cells.len());
+        cells.rotate_right(n);
+        cells[..n].fill(blank.clone());
+        // a wide char pushed to the last cell lost its right half
+        if cluster_width(&row[end - 1].text) > 1 {
+            row[end - 1].text = ' '.into();
+        }
+    }
+}
+
+// moves the cells of `row` after `x + n` left to `x`, blanks come in at `end`
+fn delete_cells(row: &mut [Node], x: usize, end: usize, n: usize, blank: &Node) {
+    let end = end.min(row.len());
+    if x < end {
+        let n = n.min(end - x);
+        cut_wide(row, x);
+        cut_wide(row, x + n);
+        cut_wide(row, end);
+        let cells = &mut row[x..end];
+        cells.rotate_left(n);
+        let len = cells.len();
+        cells[len - n..].fill(blank.clone());
+    }
+}
+
+// the regular stops every `every` columns, 0 for none, with the ones set by
+// HTS and cleared by TBC 0
+#[derive(Debug, Clone)]
+struct TabStops {
+    every: usize,
+    set: BTreeSet<usize>,
+    cleared: BTreeSet<usize>,
+}
+
+impl TabStops {
+    fn new(every: usize) -> Self {
+        TabStops {
+            every,
+            set: BTreeSet::new(),
+            cleared: BTreeSet::new(),
+        }
+    }
+
+    fn insert(&mut self, x: usize) {
+        self.cleared.remove(&x);
+        self.set.insert(x);
+    }
+
+    fn remove(&mut self, x: usize) {
+        self.set.remove(&x);
+        self.cleared.insert(x);
+    }
+
+    fn clear(&mut self) {
+        *self = TabStops::new(0);
+    }
+
+    // the first stop after column `x`
+    fn next(&self, x: usize) -> Option<usize> {
+        let set = self.set.range(x + 1..).next().copied();
+        let regular = (self.every > 0).then(|| {
+            let mut n = (x / self.every + 1) * self.every;
+            while self.cleared.contains(&n) {
+                n += self.every;
+            }
+            n
+        });
+        set.into_iter().chain(regular).min()
+    }
+
+    // the last stop before column `x`
+    fn prev(&self, x: usize) -> Option<usize> {
+        let set = self.set.range(..x).next_back().copied();
+        let regular = (self.every > 0 && x > 0)
+            .then(|| {
+                let mut n = (x - 1) / self.every * self.every;
+                while self.cleared.contains(&n) {
+                    n = n.checked_sub(self.every)?;
+                }
+                Some(n)
+            })
+            .flatten();
+        set.into_iter().chain(regular).max()
+    }
+}
+
+// the cursor saved by DECSC, its row is relative to the screen
+#[derive(Debug, Clone, Default)]
+struct SavedCursor {
+    x: usize,
+    y: usize,
+    pen: Node,
+    g0: Charset,
+    g1: Charset,
+    shift_out: bool,
+    origin_mode: bool,
+    wrap_pending: bool,
+}
+
+// the normal screen while the alternate screen is drawn on the canvas
+#[derive(Debug, Clone)]
+struct NormalScreen {
+    pixels: Vec<Vec<Node>>,
+    w: usize,
+    h: usize,
+    marks: Vec<Mark>,
+    origin: usize,
+}
+
+// cursor and current attributes while the canvas is being drawn
+#[derive(Debug, Clone)]
+struct State {
+    cur_x: usize,
+    cur_y: usize,
+    // a char was printed in the last column, the next one goes to the
+    // start of the next row
+    wrap_pending: bool,
+    // attributes and sourcemap of the next printed char
+    pen: Node,
+    g0: Charset,
+    g1: Charset,
+    // SO invoked G1
+    shift_out: bool,
+    // the char REP repeats
+    last: Option<Node>,
+    // cell of the char printed by the previous token, marks that come in
+    // the next one still join its grapheme cluster
+    cluster: Option<(usize, usize)>,
+    // DECSTBM margins, 0-based and inclusive and relative to the screen, no
+    // bottom margin means the last row of the screen or canvas
+    top: usize,
+    bottom: Option<usize>,
+    // height of the screen and the canvas row it starts at, rows above it
+    // are the scrollback
+    rows: Option<usize>,
+    origin: usize,
+    max_width: usize,
+    max_cells: usize,
+    tabs: TabStops,
+    // DEC private modes changed by the input
+    modes: HashMap<DecMode, bool>,
+    saved_cursor: Option<SavedCursor>,
+    normal: Option<NormalScreen>,
+    // the alternate screen was left without clearing it, so switching back
+    // with 47 or 1047 shows it again
+    keep_alternate: bool,
+}
+
+impl State {
+    fn new(
+        max_width: Option<usize>,
+        rows: Option<usize>,
+        max_cells: usize,
+        tab_width: usize,
+    ) -> Self {
+        State {
+            cur_x: 0,
+            cur_y: 0,
+            wrap_pending: false,
+            pen: Node::default(),
+            g0: Charset::Ascii,
+            g1: Charset::Ascii,
+            shift_out: false,
+            last: None,
+            cluster: None,
+            top: 0,
+            bottom: None,
+            rows: rows.filter(|&h| h > 0),
+            origin: 0,
+            // a width of 0 means unlimited
+            max_width: max_width.filter(|&w| w > 0).unwrap_or(usize::MAX),
+            max_cells,
+            tabs: TabStops::new(tab_width),
+            modes: HashMap::new(),
+            saved_cursor: None,
+            normal: None,
+            keep_alternate: false,
+        }
+    }
+
+    // canvas row of the last screen row
+    fn screen_bottom(&self) -> Option<usize> {
+        self.rows.map(|rows| self.origin + rows - 1)
+    }
+
+    // keeps a row on the screen
+    fn clamp_row(&self, y: usize) -> usize {
+        self.screen_bottom()
+            .map_or(y, |bottom| y.clamp(self.origin, bottom))
+    }
+
+    // stops at the last column like CUF, without a stop ahead the cursor
+    // only moves there if the width is limited
+    fn next_tab(&self, x: usize) -> usize {
+        match self.tabs.next(x) {
+            Some(n) => n.min(self.max_width - 1),
+            None if self.max_width != usize::MAX => self.max_width - 1,
+            None => x,
+        }
+    }
+
+    // the cell left by erasing, it keeps the background like xterm's BCE
+    fn blank(&self) -> Node {
+        if self.pen.bg_color == AnsiColor::Default {
+            return empty_node();
+        }
+        Node {
+            bg_color: self.pen.bg_color,
+            bg_color_r: self.pen.bg_color_r,
+            ..empty_node()
+        }
+    }
+
+    fn mode(&self, mode: DecMode) -> bool {
+        self.modes
+            .get(&mode)
+            .copied()
+            .unwrap_or(mode.default_value())
+    }
+
+    // moves the cursor past a char of `width` cells printed at `x`, after the
+    // last column the wrap is deferred to the next char
+    fn advance(&mut self, x: usize, width: usize) {
+        if x + width < self.max_width {
+            self.cur_x = x + width;
+        } else {
+            self.cur_x = self.max_width - 1;
+            self.wrap_pending = self.mode(DecMode::Autowrap);
+        }
+    }
+
+    // canvas row of screen row `n`, which is relative to the scroll region
+    // in origin mode
+    fn address_row(&self, n: usize) -> usize {
+        let mut y = self.origin + n;
+        if self.mode(DecMode::Origin) {
+            y += self.top;
+            if let Some(bottom) = self.bottom {
+                y = y.min(self.origin + bottom);
+            }
         }
+        self.clamp_row(y)
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = Some(SavedCursor {
+            x: self.cur_x,
+            y: self.cur_y - self.origin,
+            pen: self.pen.clone(),
+            g0: self.g0,
+            g1: self.g1,
+            shift_out: self.shift_out,
+            origin_mode: self.mode(DecMode::Origin),
+            wrap_pending: self.wrap_pending,
+        });
+    }
+
+    // the home position and default attributes if nothing was saved
+    fn restore_cursor(&mut self) {
+        let saved = self.saved_cursor.clone().unwrap_or_default();
+        self.cur_x = saved.x.min(self.max_width - 1);
+        self.cur_y = self.clamp_row(self.origin + saved.y);
+        self.pen = Node {
+            text_r: self.pen.text_r,
+            ..saved.pen
+        };
+        self.g0 = saved.g0;
+        self.g1 = saved.g1;
+        self.shift_out = saved.shift_out;
+        self.modes.insert(DecMode::Origin, saved.origin_mode);
+        self.wrap_pending = saved.wrap_pending;
     }
 }
 
 impl Canvas {
+    /// Draws `str` on a canvas that grows as far as the input asks, so a
+    /// cursor movement like `CSI 99999999 G` can run out of memory. Use
+    /// [`Canvas::try_new`] for input that isn't trusted.
     pub fn new<S: AsRef<str>>(str: S, max_width: Option<usize>) -> Self {
-        let s = str.as_ref();
-        let (_, lex) = parse_ansi(s).unwrap();
-        let mut cur_x = 0;
-        let mut cur_y = 0;
-        let mut cur_c = AnsiColor::Default;
-        let mut cur_bg_c = AnsiColor::Default;
-        let mut bold = false;
-        let mut dim = false;
-        let mut italic = false;
-        let mut reverse = false;
-        let mut underline = false;
-        let mut blink = false;
-        let mut strike = false;
-
-        let mut bg_color_r = (0, 0);
-        let mut color_r = (0, 0);
-        let mut bold_r = (0, 0);
-        let mut blink_r = (0, 0);
-        // let mut text_r = (0, 0);
-        let mut dim_r = (0, 0);
-        let mut italic_r = (0, 0);
-        let mut underline_r = (0, 0);
-        let mut hide_r = (0, 0);
-        let mut strike_r = (0, 0);
-
-        let mut w = 0;
-        let mut h = 0;
-        let mut pixels = Vec::new();
-        let mut hide = false;
-        let max_width = max_width.unwrap_or(usize::MAX);
-
-        let mut q = VecDeque::from(lex);
-
-        while let Some(token) = q.pop_front() {
-            let Token { sgr: i, range } = token;
-            // eprintln!("{:?} {:?}", i, range);
-
-            macro_rules! set_bg_color {
-                ($color:expr) => {
-                    if reverse && $color == AnsiColor::Default {
-                        cur_bg_c = AnsiColor::Color8(Color8::Black);
-                    } else {
-                        cur_bg_c = $color;
-                    }
+        // can't fail without a cell limit
+        Self::new_with_tabs(str, max_width, 0, TAB_WIDTH)
+    }
 
-                    bg_color_r = range;
-                };
+    /// Like [`Canvas::new`], but fails instead of growing past [`MAX_CELLS`].
+    pub fn try_new<S: AsRef<str>>(str: S, max_width: Option<usize>) -> Result<Self, Error> {
+        Self::try_new_with_tabs(str, max_width, 0, TAB_WIDTH)
+    }
+
+    /// Draws on a screen of `rows` rows like a terminal, the cursor stays on
+    /// the screen and rows scrolled off its top are kept as scrollback.
+    ///
+    /// A `rows` of 0 means unlimited, which is the same as [`Canvas::new`].
+    pub fn new_screen<S: AsRef<str>>(str: S, max_width: Option<usize>, rows: usize) -> Self {
+        Self::new_with_tabs(str, max_width, rows, TAB_WIDTH)
+    }
+
+    /// Like [`Canvas::new_screen`], but fails instead of growing past
+    /// [`MAX_CELLS`].
+    pub fn try_new_screen<S: AsRef<str>>(
+        str: S,
+        max_width: Option<usize>,
+        rows: usize,
+    ) -> Result<Self, Error> {
+        Self::try_new_with_tabs(str, max_width, rows, TAB_WIDTH)
+    }
+
+    /// Like [`Canvas::new_screen`], but with tab stops every `tab_width`
+    /// columns instead of [`TAB_WIDTH`], 0 means no stops until the input
+    /// sets them.
+    ///
+    /// Has no cell limit either, see [`Canvas::try_new_with_tabs`].
+    pub fn new_with_tabs<S: AsRef<str>>(
+        str: S,
+        max_width: Option<usize>,
+        rows: usize,
+        tab_width: usize,
+    ) -> Self {
+        // can't fail without a cell limit
+        let state = State::new(max_width, Some(rows), usize::MAX, tab_width);
+        Self::build(str.as_ref(), state).unwrap_or_default()
+    }
+
+    /// Like [`Canvas::new_with_tabs`], but fails instead of growing past
+    /// [`MAX_CELLS`].
+    pub fn try_new_with_tabs<S: AsRef<str>>(
+        str: S,
+        max_width: Option<usize>,
+        rows: usize,
+        tab_width: usize,
+    ) -> Result<Self, Error> {
+        let state = State::new(max_width, Some(rows), MAX_CELLS, tab_width);
+        Self::build(str.as_ref(), state)
+    }
+
+    fn build(s: &str, mut state: State) -> Result<Self, Error> {
+        let mut canvas = Canvas::default();
+        for token in char_tokens(s) {
+            canvas.apply(&mut state, token)?;
+        }
+        canvas.finish(&mut state);
+        Ok(canvas)
+    }
+
+    // switches back to the normal screen if the input ends on the alternate
+    // one, which is then the last alternate frame
+    fn finish(&mut self, state: &mut State) {
+        self.leave_alternate(state, DecMode::AlternateScreen);
+        self.scrollback = state.origin;
+        if let Some(alternate) = &mut self.alternate {
+            alternate.palette = self.palette.clone();
+        }
+    }
+
+    fn set_mode(&mut self, state: &mut State, mode: DecMode, value: bool) {
+        match mode {
+            DecMode::AlternateScreen
+            | DecMode::AlternateScreenClear
+            | DecMode::AlternateScreenSaveCursor => {
+                if value {
+                    self.enter_alternate(state, mode);
+                } else {
+                    self.leave_alternate(state, mode);
+                }
             }
+            _ => {}
+        }
+        state.modes.insert(mode, value);
+        // DECOM homes the cursor to the new origin
+        if mode == DecMode::Origin {
+            state.cur_x = 0;
+            state.cur_y = state.address_row(0);
+        }
+    }
 
-            macro_rules! set_color {
-                ($color:expr) => {
-                    if reverse && $color == AnsiColor::Default {
-                        cur_c = AnsiColor::Color8(Color8::White);
-                    } else {
-                        cur_c = $color;
-                    }
-                    color_r = range;
+    fn enter_alternate(&mut self, state: &mut State, mode: DecMode) {
+        if state.normal.is_some() {
+            return;
+        }
+        if mode == DecMode::AlternateScreenSaveCursor {
+            state.save_cursor();
+        }
+        let alternate = match self.alternate.take() {
+            Some(c) if state.keep_alternate && mode != DecMode::AlternateScreenSaveCursor => *c,
+            _ => Canvas::default(),
+        };
+        state.normal = Some(NormalScreen {
+            pixels: std::mem::replace(&mut self.pixels, alternate.pixels),
+            w: std::mem::replace(&mut self.w, alternate.w),
+            h: std::mem::replace(&mut self.h, alternate.h),
+            marks: std::mem::replace(&mut self.marks, alternate.marks),
+            origin: state.origin,
+        });
+        // the alternate screen has no scrollback
+        state.cur_y -= state.origin;
+        state.origin = 0;
+    }
+
+    fn leave_alternate(&mut self, state: &mut State, mode: DecMode) {
+        let Some(normal) = state.normal.take() else {
+            return;
+        };
+        self.alternate = Some(Box::new(Canvas {
+            pixels: std::mem::replace(&mut self.pixels, normal.pixels),
+            w: std::mem::replace(&mut self.w, normal.w),
+            h: std::mem::replace(&mut self.h, normal.h),
+            marks: std::mem::replace(&mut self.marks, normal.marks),
+            ..Canvas::default()
+        }));
+        // 1047 clears the alternate screen on the way out, 1049 on the way in
+        state.keep_alternate = mode == DecMode::AlternateScreen;
+        state.origin = normal.origin;
+        if mode == DecMode::AlternateScreenSaveCursor {
+            state.restore_cursor();
+        } else {
+            state.cur_y = state.clamp_row(state.origin + state.cur_y);
+        }
+    }
+
+    /// Returns the rows of `view`, cropped to `w` columns.
+    pub fn view(&self, view: View) -> Canvas {
+        let start = match view {
+            View::Screen => self.scrollback,
+            View::All => 0,
+            View::Last(n) => self.h.saturating_sub(n),
+        };
+        let pixels = self.pixels[start.min(self.pixels.len())..self.h.min(self.pixels.len())]
+            .iter()
+            .map(|row| row[..self.w.min(row.len())].to_vec())
+            .collect();
+        let marks = self
+            .marks
+            .iter()
+            .filter(|m| (start..self.h).contains(&m.y))
+            .map(|m| Mark {
+                y: m.y - start,
+                ..*m
+            })
+            .collect();
+        Canvas {
+            pixels,
+            w: self.w,
+            h: self.h - start.min(self.h),
+            palette: self.palette.clone(),
+            marks,
+            scrollback: self.scrollback.saturating_sub(start),
+            alternate: self.alternate.clone(),
+        }
+    }
+
+    // the cursor stays in the last column after a char is printed there,
+    // like xterm it only wraps when the next char comes
+    fn put_char(&mut self, state: &mut State, node: Node) {
+        let width = str_width(&node.text).clamp(1, state.max_width.min(2));
+        if state.wrap_pending {
+            state.wrap_pending = false;
+            state.cur_x = 0;
+            self.index(state);
+        }
+        // a wide char that does not fit in the row goes to the next one
+        if state.cur_x.saturating_add(width) > state.max_width && state.mode(DecMode::Autowrap) {
+            state.cur_x = 0;
+            self.index(state);
+        }
+        // without autowrap the last columns are overwritten
+        let x = state.cur_x.min(state.max_width - width);
+        let y = state.cur_y;
+        ensure_shape(&mut self.pixels, x + width - 1, y);
+        split_wide(&mut self.pixels[y], x, width);
+        for i in 1..width {
+            let continuation = Node {
+                text: String::new(),
+                ..node.clone()
+            };
+            set_node(&mut self.pixels, continuation, x + i, y);
+        }
+        set_node(&mut self.pixels, node, x, y);
+        state.cluster = Some((x, y));
+        state.advance(x, width);
+    }
+
+    // prints a grapheme cluster, or adds the marks a token starts with to the
+    // cluster printed by the previous one, e.g. when a chunk ends between them
+    fn print(
+        &mut self,
+        state: &mut State,
+        cluster: Option<(usize, usize)>,
+        text: String,
+        range: (usize, usize),
+    ) {
+        if let Some((x, y)) = cluster
+            && let Some(c) = text.chars().next()
+            && extends(&self.pixels[y][x].text, c)
+        {
+            let cell = &mut self.pixels[y][x];
+            let width = cluster_width(&cell.text);
+            cell.text.push_str(&text);
+            cell.text_r = merge_range(cell.text_r, range);
+            let node = cell.clone();
+            // e.g. a variation selector that asks for the emoji presentation
+            if width == 1 && cluster_width(&node.text) == 2 && state.cur_x == x + 1 {
+                ensure_shape(&mut self.pixels, x + 1, y);
+                split_wide(&mut self.pixels[y], x + 1, 1);
+                let continuation = Node {
+                    text: String::new(),
+                    ..node.clone()
                 };
+                set_node(&mut self.pixels, continuation, x + 1, y);
+                state.advance(x, 2);
             }
+            state.last = Some(node);
+            state.cluster = Some((x, y));
+            return;
+        }
+        // zero width chars with no cell to join are dropped
+        if cluster_width(&text) == 0 {
+            return;
+        }
 
-            match i {
-                Sgr::LineFeed => {
-                    cur_y += 1;
-                    cur_x = 0;
-                    ensure_shape(&mut pixels, w, cur_y);
-                }
+        let node = Node {
+            text,
+            text_r: range,
+            ..state.pen.clone()
+        };
+        state.last = Some(node.clone());
+        self.put_char(state, node);
+    }
 
-                Sgr::Char(c) => {
-                    // text_r = range;
-                    let node = Node {
-                        text: c.into(),
-                        bg_color: cur_bg_c,
-                        color: cur_c,
-                        bold,
-                        blink,
-                        dim,
-                        italic,
-                        underline,
-                        hide,
-                        strike,
-
-                        bg_color_r,
-                        color_r,
-                        bold_r,
-                        blink_r,
-                        text_r: range,
-                        dim_r,
-                        italic_r,
-                        underline_r,
-                        hide_r,
-                        strike_r,
-                    };
-                    if cur_x >= max_width {
-                        cur_x = 0;
-                        cur_y += 1;
-                    }
-                    set_node(&mut pixels, node, cur_x, cur_y);
-                    cur_x += 1;
-                }
-                Sgr::ColorBackground(c) => {
-                    if reverse {
-                        set_color!(c);
-                    } else {
-                        set_bg_color!(c);
-                    }
-                }
-                Sgr::ColorForeground(c) => {
-                    if reverse {
-                        set_bg_color!(c);
-                    } else {
-                        set_color!(c);
-                    }
-                }
-                Sgr::ColorFgBg(fg, bg) => {
-                    if reverse {
-                        set_color!(bg);
-                        set_bg_color!(fg);
-                    } else {
-                        set_color!(fg);
-                        set_bg_color!(bg);
-                    }
-                }
-                Sgr::Bold => {
-                    bold = true;
-                    bold_r = range;
-                }
-                Sgr::Italic => {
-                    italic = true;
-                    italic_r = range;
-                }
-                Sgr::UnItalic => {
-                    italic = false;
-                    italic_r = range;
-                }
-                Sgr::Underline => {
-                    underline = true;
-                    underline_r = range;
+    // top and bottom row of the scroll region, without a screen rows past
+    // the bottom of the canvas are not part of it
+    fn margins(&self, state: &State) -> (usize, usize) {
+        let last = state
+            .screen_bottom()
+            .unwrap_or(self.h.max(state.cur_y + 1) - 1);
+        let bottom = state.bottom.map_or(last, |b| state.origin + b).min(last);
+        ((state.origin + state.top).min(bottom), bottom)
+    }
+
+    fn scroll_up(&mut self, state: &mut State, n: usize) {
+        let (top, bottom) = self.margins(state);
+        // the top rows of a whole normal screen go to the scrollback
+        if let Some(rows) = state.rows
+            && state.normal.is_none()
+            && top == state.origin
+            && Some(bottom) == state.screen_bottom()
+        {
+            let n = n.min(rows);
+            state.origin += n;
+            state.cur_y += n;
+            // the rows coming in at the bottom are blanked like erased ones
+            let blank = state.blank();
+            if blank.bg_color != AnsiColor::Default {
+                ensure_shape(&mut self.pixels, self.w, bottom + n);
+                for row in self.pixels[bottom + 1..=bottom + n].iter_mut() {
+                    row.fill(blank.clone());
                 }
-                Sgr::UnUnderlined => {
-                    underline = false;
-                    underline_r = range;
+            }
+            return;
+        }
+        self.delete_rows(top, bottom, n, &state.blank());
+    }
+
+    fn scroll_down(&mut self, state: &State, n: usize) -> Result<(), Error> {
+        let (top, _) = self.margins(state);
+        self.open_rows(state, top, n)
+    }
+
+    // inserts `n` blank rows at `y`, without a screen or a bottom margin the
+    // canvas grows instead of losing the rows moved past its last one
+    fn open_rows(&mut self, state: &State, y: usize, n: usize) -> Result<(), Error> {
+        let (_, mut bottom) = self.margins(state);
+        if state.rows.is_none() && state.bottom.is_none() {
+            self.grow_rows(state, bottom.saturating_add(n).saturating_add(1))?;
+            bottom += n;
+        }
+        self.insert_rows(y, bottom, n, &state.blank());
+        Ok(())
+    }
+
+    // SU without a screen or a scroll region, the rows scrolled off the top
+    // stay like the scrollback of a screen and `n` blank ones come in below
+    fn append_rows(&mut self, state: &mut State, n: usize) -> Result<(), Error> {
+        let (_, bottom) = self.margins(state);
+        self.grow_rows(state, bottom.saturating_add(n).saturating_add(1))?;
+        let blank = state.blank();
+        if blank.bg_color != AnsiColor::Default {
+            for row in self.pixels[bottom + 1..=bottom + n].iter_mut() {
+                row.fill(blank.clone());
+            }
+        }
+        state.cur_y += n;
+        Ok(())
+    }
+
+    // makes the canvas at least `h` rows high
+    fn grow_rows(&mut self, state: &State, h: usize) -> Result<(), Error> {
+        let w = self.w.max(state.cur_x.saturating_add(1));
+        if w.saturating_mul(h) > state.max_cells {
+            return Err(Error::TooLarge { w, h });
+        }
+        self.h = self.h.max(h);
+        ensure_shape(&mut self.pixels, self.w, self.h);
+        Ok(())
+    }
+
+    // moves rows `top..=bottom` down by `n`, rows moved past `bottom` are lost
+    fn insert_rows(&mut self, top: usize, bottom: usize, n: usize, blank: &Node) {
+        ensure_shape(&mut self.pixels, self.w, bottom);
+        let rows = &mut self.pixels[top..=bottom];
+        let n = n.min(rows.len());
+        rows.rotate_right(n);
+        for row in rows[..n].iter_mut() {
+            row.fill(blank.clone());
+        }
+    }
+
+    // moves rows `top..=bottom` up by `n`, blank rows come in at `bottom`
+    fn delete_rows(&mut self, top: usize, bottom: usize, n: usize, blank: &Node) {
+        ensure_shape(&mut self.pixels, self.w, bottom);
+        let rows = &mut self.pixels[top..=bottom];
+        let n = n.min(rows.len());
+        rows.rotate_left(n);
+        let len = rows.len();
+        for row in rows[len - n..].iter_mut() {
+            row.fill(blank.clone());
+        }
+    }
+
+    // a colored erase to the right edge paints up to a limited width, like
+    // the bars drawn with `CSI K`
+    fn erase_width(&mut self, state: &State, blank: &Node) -> usize {
+        if blank.bg_color != AnsiColor::Default && state.max_width != usize::MAX {
+            self.w = self.w.max(state.max_width);
+            ensure_shape(&mut self.pixels, self.w, self.h);
+        }
+        self.w
+    }
+
+    // the cells ICH pushes right only fall off a limited width, otherwise
+    // the canvas grows to keep them
+    fn insert_width(&mut self, state: &State, n: usize) -> Result<usize, Error> {
+        let used = self.pixels.get(state.cur_y).map_or(0, |row| {
+            row.iter()
+                .rposition(|c| *c != empty_node())
+                .map_or(0, |i| i + 1)
+        });
+        let w = self.w.max(used.saturating_add(n).min(state.max_width));
+        if w > self.w {
+            if w.saturating_mul(self.h) > state.max_cells {
+                return Err(Error::TooLarge { w, h: self.h });
+            }
+            self.w = w;
+            ensure_shape(&mut self.pixels, self.w, self.h);
+        }
+        Ok(self.w)
+    }
+
+    // drops the scrollback, marks in it are dropped too
+    fn clear_scrollback(&mut self, state: &mut State) {
+        let n = state.origin.min(self.pixels.len());
+        self.pixels.drain(..n);
+        self.h -= n.min(self.h);
+        self.marks.retain(|m| m.y >= n);
+        for mark in self.marks.iter_mut() {
+            mark.y -= n;
+        }
+        state.origin -= n;
+        state.cur_y -= n;
+    }
+
+    // moves down a row, scrolling the region at its bottom margin
+    fn index(&mut self, state: &mut State) {
+        let (_, bottom) = self.margins(state);
+        if (state.bottom.is_some() || state.rows.is_some()) && state.cur_y == bottom {
+            self.scroll_up(state, 1);
+        } else {
+            state.cur_y = state.clamp_row(state.cur_y + 1);
+        }
+    }
+
+    fn apply(&mut self, state: &mut State, token: Token) -> Result<(), Error> {
+        let Token { sgr: i, range } = token;
+        let pixels = &mut self.pixels;
+        let w = self.w;
+        let h = self.h;
+        let max_width = state.max_width;
+        // eprintln!("{:?} {:?}", i, range);
+        let cluster = state.cluster.take();
+
+        macro_rules! set_bg_color {
+            ($color:expr) => {{
+                state.pen.bg_color = $color;
+                state.pen.bg_color_r = range;
+            }};
+        }
+
+        macro_rules! set_color {
+            ($color:expr) => {{
+                state.pen.color = $color;
+                state.pen.color_r = range;
+            }};
+        }
+
+        // cursor controls cancel a pending wrap, the cursor is in the last
+        // column already
+        if matches!(
+            i,
+            Sgr::LineFeed
+                | Sgr::ReverseIndex
+                | Sgr::CarriageReturn
+                | Sgr::Backspace
+                | Sgr::Tab
+                | Sgr::CursorForwardTab(_)
+                | Sgr::CursorBackwardTab(_)
+                | Sgr::CursorUp(_)
+                | Sgr::CursorDown(_)
+                | Sgr::CursorForward(_)
+                | Sgr::CursorBack(_)
+                | Sgr::CursorNextLine(_)
+                | Sgr::CursorPreviousLine(_)
+                | Sgr::CursorHorizontalAbsolute(_)
+                | Sgr::CharacterPositionAbsolute(_)
+                | Sgr::LinePositionAbsolute(_)
+                | Sgr::CursorPosition(..)
+                | Sgr::HorizontalVerticalPosition(..)
+                | Sgr::SetScrollRegion(..)
+                | Sgr::InsertCharacter(_)
+                | Sgr::DeleteCharacter(_)
+                | Sgr::InsertLine(_)
+                | Sgr::DeleteLine(_)
+                | Sgr::Decset(_)
+                | Sgr::Decrst(_)
+        ) {
+            state.wrap_pending = false;
+        }
+
+        match i {
+            Sgr::LineFeed => {
+                self.index(state);
+                state.cur_x = 0;
+            }
+            Sgr::ReverseIndex => {
+                if state.cur_y == self.margins(state).0 {
+                    self.scroll_down(state, 1)?;
+                } else {
+                    state.cur_y = state.clamp_row(state.cur_y.saturating_sub(1));
                 }
-                Sgr::Dim => {
-                    dim = true;
-                    dim_r = range;
+            }
+            Sgr::ScrollUp(n) => {
+                let n = n.max(1) as usize;
+                if state.rows.is_none() && state.bottom.is_none() && state.top == 0 {
+                    self.append_rows(state, n)?;
+                } else {
+                    self.scroll_up(state, n);
                 }
-                Sgr::ColorReset => {
-                    bold = false;
-                    dim = false;
-                    italic = false;
-                    underline = false;
-                    reverse = false;
-                    cur_bg_c = AnsiColor::Default;
-                    cur_c = AnsiColor::Default;
-                    blink = false;
-                    hide = false;
-                    strike = false;
-
-                    bold_r = range;
-                    dim_r = range;
-                    italic_r = range;
-                    underline_r = range;
-                    bg_color_r = range;
-                    color_r = range;
-                    blink_r = range;
-                    hide_r = range;
-                    strike_r = range;
+            }
+            Sgr::ScrollDown(n) => self.scroll_down(state, n.max(1) as usize)?,
+            Sgr::SetScrollRegion(top, bottom) => {
+                let top = (top - 1).max(0) as usize;
+                let bottom = (bottom > 0).then(|| bottom as usize - 1);
+                // xterm ignores a region of less than two rows
+                if bottom.is_none_or(|bottom| top < bottom) {
+                    state.top = top;
+                    state.bottom = bottom;
+                    state.cur_x = 0;
+                    state.cur_y = state.address_row(0);
                 }
-                Sgr::CursorUp(c) => cur_y = cur_y.saturating_sub(c as usize),
-                Sgr::CursorDown(c) => {
-                    cur_y += c as usize;
-                    ensure_shape(&mut pixels, w, cur_y);
+            }
+
+            Sgr::Char(c) => {
+                let charset = if state.shift_out { state.g1 } else { state.g0 };
+                let c = charset.map(c);
+                self.print(state, cluster, c.into(), range);
+            }
+            Sgr::Grapheme(s) => self.print(state, cluster, s, range),
+            // SUB is shown as a substitute character
+            Sgr::Control('\x1a') => self.print(state, cluster, '␦'.into(), range),
+            Sgr::ColorBackground(c) => set_bg_color!(c),
+            Sgr::ColorForeground(c) => set_color!(c),
+            Sgr::ColorFgBg(fg, bg) => {
+                set_color!(fg);
+                set_bg_color!(bg);
+            }
+            Sgr::CursorSave | Sgr::DecSaveCursor => state.save_cursor(),
+            Sgr::CursorRestore | Sgr::DecRestoreCursor => state.restore_cursor(),
+            Sgr::DesignateG0(c) => state.g0 = c,
+            Sgr::DesignateG1(c) => state.g1 = c,
+            Sgr::ShiftOut => state.shift_out = true,
+            Sgr::ShiftIn => state.shift_out = false,
+            Sgr::Bold => {
+                state.pen.bold = true;
+                state.pen.bold_r = range;
+            }
+            Sgr::Italic => {
+                state.pen.italic = true;
+                state.pen.italic_r = range;
+            }
+            Sgr::UnItalic => {
+                state.pen.italic = false;
+                state.pen.italic_r = range;
+            }
+            Sgr::Underline | Sgr::UnderlineStyle(_) => {
+                state.pen.underline = true;
+                state.pen.underline_r = range;
+            }
+            Sgr::UnUnderlined => {
+                state.pen.underline = false;
+                state.pen.underline_r = range;
+            }
+            Sgr::Dim => {
+                state.pen.dim = true;
+                state.pen.dim_r = range;
+            }
+            Sgr::ColorReset => {
+                state.pen = Node {
+                    bg_color_r: range,
+                    color_r: range,
+                    bold_r: range,
+                    blink_r: range,
+                    text_r: state.pen.text_r,
+                    dim_r: range,
+                    italic_r: range,
+                    underline_r: range,
+                    conceal_r: range,
+                    strike_r: range,
+                    reverse_r: range,
+                    ..Node::default()
+                };
+            }
+            Sgr::CursorUp(c) => {
+                state.cur_y = state.clamp_row(state.cur_y.saturating_sub(c as usize))
+            }
+            Sgr::CursorDown(c) => state.cur_y = state.clamp_row(state.cur_y + c as usize),
+            Sgr::CursorBack(c) => state.cur_x = state.cur_x.saturating_sub(c as usize),
+            Sgr::CursorForward(c) => {
+                state.cur_x = state.cur_x.saturating_add(c as usize).min(max_width - 1)
+            }
+            Sgr::Backspace => state.cur_x = state.cur_x.saturating_sub(1),
+            Sgr::Tab => state.cur_x = state.next_tab(state.cur_x),
+            Sgr::CursorForwardTab(n) => {
+                for _ in 0..n.max(1) {
+                    state.cur_x = state.next_tab(state.cur_x);
                 }
-                Sgr::CursorBack(c) => cur_x = cur_x.saturating_sub(c as usize),
-                Sgr::CursorForward(c) => {
-                    cur_x += c as usize;
-                    if cur_x >= max_width {
-                        cur_x %= max_width;
-                        cur_y += 1;
-                    }
-                    ensure_shape(&mut pixels, cur_x, cur_y);
+            }
+            Sgr::CursorBackwardTab(n) => {
+                for _ in 0..n.max(1) {
+                    state.cur_x = state.tabs.prev(state.cur_x).unwrap_or(0);
                 }
-                Sgr::Backspace => cur_x = cur_x.saturating_sub(1),
-                Sgr::Tab => {
-                    let tail = cur_x & 7;
-                    if tail == 0 {
-                        cur_x += 8
-                    } else {
-                        cur_x += 8 - tail;
-                    }
+            }
+            Sgr::TabSet => state.tabs.insert(state.cur_x),
+            Sgr::TabClear(0) => state.tabs.remove(state.cur_x),
+            Sgr::TabClear(3) => state.tabs.clear(),
+
+            Sgr::CarriageReturn => state.cur_x = 0,
 
-                    if cur_x >= max_width {
-                        cur_x %= max_width;
-                        cur_y += 1;
+            Sgr::CursorNextLine(n) => {
+                state.cur_y = state.clamp_row(state.cur_y + n as usize);
+                state.cur_x = 0;
+            }
+            Sgr::CursorPreviousLine(n) => {
+                state.cur_y = state.clamp_row(state.cur_y.saturating_sub(n as usize));
+                state.cur_x = 0;
+            }
+            Sgr::CursorHorizontalAbsolute(n) | Sgr::CharacterPositionAbsolute(n) => {
+                state.cur_x = ((n - 1).max(0) as usize).min(max_width - 1);
+            }
+            Sgr::LinePositionAbsolute(n) => {
+                state.cur_y = state.address_row((n - 1).max(0) as usize)
+            }
+            Sgr::CursorPosition(row, col) | Sgr::HorizontalVerticalPosition(row, col) => {
+                state.cur_y = state.address_row((row - 1).max(0) as usize);
+                state.cur_x = ((col - 1).max(0) as usize).min(max_width - 1);
+            }
+            Sgr::Repeat(n) => {
+                if let Some(node) = state.last.clone() {
+                    // past a whole screen every row of repeats only scrolls
+                    // it, so a huge count is cut to one that leaves the same
+                    // screen, and no canvas holds more than `MAX_CELLS` of them
+                    let mut n = n.max(1) as usize;
+                    let per_row = max_width / str_width(&node.text).clamp(1, max_width.min(2));
+                    if let Some(rows) = state.rows
+                        && max_width != usize::MAX
+                        && n > rows.saturating_mul(per_row)
+                    {
+                        let screen = rows * per_row;
+                        n = screen + (n - screen) % per_row;
+                    }
+                    for _ in 0..n.min(MAX_CELLS) {
+                        self.grown_size(state)?;
+                        self.put_char(state, node.clone());
                     }
-                    ensure_shape(&mut pixels, w, cur_y);
                 }
+            }
+            Sgr::SlowBlink | Sgr::RapidBlink => state.pen.blink = true,
+            Sgr::UnBlink => state.pen.blink = false,
+            Sgr::Reverse => {
+                state.pen.reverse = true;
+                state.pen.reverse_r = range;
+            }
+            Sgr::NormalIntensity => {
+                state.pen.dim = false;
+                state.pen.bold = false;
+
+                state.pen.dim_r = range;
+                state.pen.bold_r = range;
+            }
+            Sgr::UnReversed => {
+                state.pen.reverse = false;
+                state.pen.reverse_r = range;
+            }
+            Sgr::Strike => {
+                state.pen.strike = true;
+                state.pen.strike_r = range;
+            }
+            Sgr::UnStrike => {
+                state.pen.strike = false;
+                state.pen.strike_r = range;
+            }
+            Sgr::ColorDefaultForeground => set_color!(AnsiColor::Default),
+            Sgr::ColorDefaultBackground => set_bg_color!(AnsiColor::Default),
 
-                Sgr::CarriageReturn => cur_x = 0,
+            Sgr::Link(_, title) => {
+                if title.contains("\x1b") {
+                    // FIXME: Avoid the influence of styles in link on subsequent characters
+                    self.apply(
+                        state,
+                        Token {
+                            range,
+                            sgr: Sgr::Underline,
+                        },
+                    )?;
+                    for mut i in char_tokens(&title) {
+                        i.range = offset_range(i.range, range);
+                        self.apply(state, i)?;
+                    }
+                    return self.apply(
+                        state,
+                        Token {
+                            range,
+                            sgr: Sgr::ColorReset,
+                        },
+                    );
+                } else {
+                    let mut k = 0;
+                    for i in graphemes(&title) {
+                        let start = k;
+                        k += i.chars().count();
+                        if i == "\n" {
+                            state.wrap_pending = false;
+                            state.cur_x = 0;
+                            state.cur_y += 1;
+                            ensure_shape(&mut self.pixels, w, state.cur_y);
+                            continue;
+                        }
 
-                Sgr::CursorNextLine(n) => {
-                    cur_y += n as usize;
-                    cur_x = 0;
-                    ensure_shape(&mut pixels, w, cur_y);
-                }
-                Sgr::CursorPreviousLine(n) => {
-                    cur_y = cur_y.saturating_sub(n as usize);
-                    cur_x = 0;
-                    ensure_shape(&mut pixels, w, cur_y);
+                        let node = Node {
+                            text: i.into(),
+                            underline: true,
+                            text_r: (range.0 + start, range.1 + start),
+                            ..state.pen.clone()
+                        };
+                        self.put_char(state, node);
+                    }
                 }
-                Sgr::CursorHorizontalAbsolute(n) => cur_x = (n - 1).max(0) as usize,
-                Sgr::CursorPosition(x, y) => {
-                    cur_x = x as usize;
-                    cur_y = y as usize;
-                    ensure_shape(&mut pixels, w, cur_y);
+            }
+            Sgr::Decset(v) => {
+                for mode in v {
+                    self.set_mode(state, mode, true);
                 }
-                Sgr::SlowBlink | Sgr::RapidBlink => blink = true,
-                Sgr::UnBlink => blink = false,
-                Sgr::Reverse => {
-                    reverse = true;
-                    let tmp_c = cur_c;
-                    let tmp_bg_c = cur_bg_c;
-                    set_color!(tmp_bg_c);
-                    set_bg_color!(tmp_c);
+            }
+            Sgr::Decrst(v) => {
+                for mode in v {
+                    self.set_mode(state, mode, false);
                 }
-                Sgr::NormalIntensity => {
-                    dim = false;
-                    bold = false;
+            }
+            Sgr::Conceal => {
+                state.pen.conceal = true;
+                state.pen.conceal_r = range;
+            }
+            Sgr::Reveal => {
+                state.pen.conceal = false;
+                state.pen.conceal_r = range;
+            }
+            Sgr::DoublyUnderlined => {
+                state.pen.bold = false;
+                state.pen.underline = true;
 
-                    dim_r = range;
-                    bold_r = range;
-                }
-                Sgr::UnReversed => {
-                    reverse = false;
-                    set_bg_color!(AnsiColor::Default);
-                    set_color!(AnsiColor::Default);
+                state.pen.bold_r = range;
+                state.pen.underline_r = range;
+            }
+            Sgr::List(v) => {
+                for i in v {
+                    self.apply(state, Token { range, sgr: i })?;
                 }
-                Sgr::Strike => {
-                    strike = true;
-                    strike_r = range;
+                return Ok(());
+            }
+            Sgr::EraseInDisplay(n) => {
+                let blank = state.blank();
+                let (x, y) = (state.cur_x, state.cur_y);
+                let w = self.erase_width(state, &blank);
+                let pixels = &mut self.pixels;
+                match n {
+                    0 => erase(pixels, (x, y), (w, h), &blank),
+                    1 => erase(pixels, (0, state.origin), (x + 1, y), &blank),
+                    2 => erase(pixels, (0, state.origin), (w, h), &blank),
+                    3 => self.clear_scrollback(state),
+                    _ => {}
                 }
-                Sgr::UnStrike => {
-                    strike = false;
-                    strike_r = range;
+            }
+            Sgr::EraseInLine(n) => {
+                let blank = state.blank();
+                let (x, y) = (state.cur_x, state.cur_y);
+                let w = self.erase_width(state, &blank);
+                let pixels = &mut self.pixels;
+                match n {
+                    0 => erase(pixels, (x, y), (w, y), &blank),
+                    1 => erase(pixels, (0, y), (x + 1, y), &blank),
+                    2 => erase(pixels, (0, y), (w, y), &blank),
+                    _ => {}
                 }
-                Sgr::ColorDefaultForeground => {
-                    if reverse {
-                        set_bg_color!(AnsiColor::Default);
-                    } else {
-                        set_color!(AnsiColor::Default);
-                    }
+            }
+            Sgr::EraseCharacter(n) => {
+                let (x, y) = (state.cur_x, state.cur_y);
+                let end = (x + n.max(1) as usize).min(w);
+                erase(pixels, (x, y), (end, y), &state.blank());
+            }
+            Sgr::InsertCharacter(n) => {
+                let n = n.max(1) as usize;
+                let w = self.insert_width(state, n)?;
+                if let Some(row) = self.pixels.get_mut(state.cur_y) {
+                    insert_cells(row, state.cur_x, w, n, &state.blank());
                 }
-                Sgr::ColorDefaultBackground => {
-                    if reverse {
-                        set_color!(AnsiColor::Default);
-                    } else {
-                        set_bg_color!(AnsiColor::Default);
-                    }
+            }
+            Sgr::DeleteCharacter(n) => {
+                if let Some(row) = pixels.get_mut(state.cur_y) {
+                    delete_cells(row, state.cur_x, w, n.max(1) as usize, &state.blank());
                 }
-
-                Sgr::Link(_, title) => {
-                    if title.contains("\x1b") {
-                        if let Ok((_, tokens)) = parse_ansi(&title) {
-                            // FIXME: Avoid the influence of styles in link on subsequent characters
-                            q.push_front(Token {
-                                range,
-                                sgr: Sgr::ColorReset,
-                            });
-                            for mut i in tokens.into_iter().rev() {
-                                i.range = offset_range(i.range, range);
-                                q.push_front(i);
-                            }
-                            q.push_front(Token {
-                                range,
-                                sgr: Sgr::Underline,
-                            });
-                        }
+            }
+            Sgr::InsertLine(n) | Sgr::DeleteLine(n) => {
+                let (top, bottom) = self.margins(state);
+                let blank = state.blank();
+                // outside the scroll region they do nothing
+                if (top..=bottom).contains(&state.cur_y) {
+                    if matches!(i, Sgr::InsertLine(_)) {
+                        self.open_rows(state, state.cur_y, n.max(1) as usize)?;
                     } else {
-                        for (k, i) in title.chars().enumerate() {
-                            if i == '\n' {
-                                cur_x = 0;
-                                cur_y += 1;
-                                ensure_shape(&mut pixels, w, cur_y);
-                                continue;
-                            }
-
-                            let node = Node {
-                                text: i.into(),
-                                bg_color: cur_bg_c,
-                                color: cur_c,
-                                bold,
-                                blink,
-                                dim,
-                                italic,
-                                underline: true,
-                                hide,
-                                strike,
-
-                                bg_color_r,
-                                color_r,
-                                bold_r,
-                                blink_r,
-                                text_r: (range.0 + k, range.1 + k),
-                                dim_r,
-                                italic_r,
-                                underline_r,
-                                hide_r,
-                                strike_r,
-                            };
-
-                            if cur_x >= max_width {
-                                cur_x = 0;
-                                cur_y += 1;
-                            }
-                            set_node(&mut pixels, node, cur_x, cur_y);
-                            cur_x += 1;
-                        }
+                        self.delete_rows(state.cur_y, bottom, n.max(1) as usize, &blank);
                     }
+                    state.cur_x = 0;
                 }
-                Sgr::CursorHide => {
-                    hide = true;
-                    hide_r = range;
-                }
-                Sgr::UnHide => {
-                    hide = false;
-                    hide_r = range;
-                }
-                Sgr::DoublyUnderlined => {
-                    bold = false;
-                    underline = true;
-
-                    bold_r = range;
-                    underline_r = range;
-                }
-                Sgr::List(v) => {
-                    for i in v.into_iter().rev() {
-                        q.push_front(Token {
-                            range: (0, 0),
-                            sgr: i,
-                        });
-                    }
+            }
+            Sgr::SetPalette(n, rgb) => {
+                self.palette.colors.insert(n, rgb);
+            }
+            Sgr::ResetPalette(v) => {
+                if v.is_empty() {
+                    self.palette.colors.clear();
                 }
-                Sgr::EraseInDisplay(n) => match n {
-                    0 => {
-                        erase(&mut pixels, cur_x, cur_y, w, h);
-                    }
-                    _ => {
-                        erase(&mut pixels, 0, 0, w, h);
-                    }
-                },
-                Sgr::EraseInLine(_) => {
-                    erase(&mut pixels, cur_x, cur_y, w, cur_y);
+                for n in v {
+                    self.palette.colors.remove(&n);
                 }
-                _ => {}
             }
+            Sgr::SetDefaultForeground(rgb) => self.palette.foreground = Some(rgb),
+            Sgr::SetDefaultBackground(rgb) => self.palette.background = Some(rgb),
+            Sgr::ResetDefaultForeground => self.palette.foreground = None,
+            Sgr::ResetDefaultBackground => self.palette.background = None,
+            Sgr::Prompt(kind) => self.marks.push(Mark {
+                x: state.cur_x,
+                y: state.cur_y,
+                kind,
+            }),
+            _ => {}
+        }
+
+        // cursor movements only grow the canvas here, after the size is checked
+        let (w, h) = self.grown_size(state)?;
+        self.w = w;
+        self.h = h;
+        ensure_shape(&mut self.pixels, self.w, self.h);
+        Ok(())
+    }
 
-            w = w.max(cur_x + 1);
-            h = h.max(cur_y + 1);
-            ensure_shape(&mut pixels, w, h);
+    // size of the canvas once it grows to the cursor
+    fn grown_size(&self, state: &State) -> Result<(usize, usize), Error> {
+        let mut w = self.w.max(state.cur_x.saturating_add(1));
+        let mut h = self.h.max(state.cur_y.saturating_add(1));
+        // a screen is always drawn whole
+        if let Some(bottom) = state.screen_bottom() {
+            h = h.max(bottom + 1);
+            if state.max_width != usize::MAX {
+                w = state.max_width;
+            }
+        }
+        if w.saturating_mul(h) > state.max_cells {
+            return Err(Error::TooLarge { w, h });
         }
+        Ok((w, h))
+    }
 
-        Canvas { pixels, w, h }
+    /// The prompt, command and output zones marked by `OSC 133`.
+    pub fn zones(&self) -> Vec<Zone> {
+        zones(&self.marks, self.h)
     }
 
     pub fn minify(&self) -> Vec<Vec<Node>> {
-        let mut v = vec![];
-        for row in &self.pixels {
-            let row_len = row.len();
+        self.pixels.iter().map(|row| minify_row(row)).collect()
+    }
+}
 
-            if row_len == 0 {
-                v.push(vec![]);
-                continue;
-            }
+/// Merges adjacent nodes with the same style into one node.
+pub fn minify_row(row: &[Node]) -> Vec<Node> {
+    let Some(first) = row.first() else {
+        return vec![];
+    };
 
-            let mut block = row[0].clone();
+    // a wide char is kept in a node of its own so it can be sized to two cells
+    let wide_at = |x: usize| row.get(x + 1).is_some_and(|c| c.is_continuation());
 
-            let mut list = vec![];
-            for c in row.iter().take(row_len).skip(1) {
-                if c.same_style(&block) {
-                    block.text.push_str(&c.text);
-                    block.text_r = merge_range(block.text_r, c.text_r);
-                } else {
-                    list.push(block.clone());
-                    block = c.clone();
-                }
+    let mut block = first.clone();
+    let mut block_wide = wide_at(0);
+
+    let mut list = vec![];
+    for (x, c) in row.iter().enumerate().skip(1) {
+        if c.is_continuation() || (c.same_style(&block) && !block_wide && !wide_at(x)) {
+            block.text.push_str(&c.text);
+            block.text_r = merge_range(block.text_r, c.text_r);
+        } else {
+            list.push(block.clone());
+            block = c.clone();
+            block_wide = wide_at(x);
+        }
+    }
+
+    list.push(block);
+    list
+}
+
+// first row of the command that is still running
+fn open_command(marks: &[Mark]) -> Option<usize> {
+    let mut start = None;
+    for mark in marks {
+        match mark.kind {
+            PromptMark::PromptStart => start = Some(mark.y),
+            PromptMark::CommandStart | PromptMark::OutputStart => {
+                start = start.or(Some(mark.y));
             }
+            PromptMark::CommandEnd(_) => start = None,
+            PromptMark::Other(_) => {}
+        }
+    }
+    start
+}
+
+/// A canvas that is fed input in chunks.
+///
+/// Rows above the cursor are considered finished and can be taken out with
+/// [`CanvasStream::take_rows`], after which the cursor can no longer move
+/// back into them. Taken rows are padded to the width known at that time.
+#[derive(Debug, Clone)]
+pub struct CanvasStream {
+    lexer: Lexer,
+    canvas: Canvas,
+    state: State,
+}
+
+impl CanvasStream {
+    pub fn new(max_width: Option<usize>) -> Self {
+        Self::new_with_tabs(max_width, TAB_WIDTH)
+    }
 
-            list.push(block);
-            v.push(list);
+    /// Like [`CanvasStream::new`], see [`Canvas::new_with_tabs`].
+    pub fn new_with_tabs(max_width: Option<usize>, tab_width: usize) -> Self {
+        CanvasStream {
+            lexer: Lexer::new(),
+            canvas: Canvas::default(),
+            state: State::new(max_width, None, MAX_CELLS, tab_width),
         }
+    }
+
+    /// Fails if the held rows would grow past [`MAX_CELLS`], the stream
+    /// shouldn't be fed after that.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        for token in self.lexer.feed_chars(chunk) {
+            self.canvas.apply(&mut self.state, token)?;
+        }
+        Ok(())
+    }
+
+    /// The rows that are still held.
+    pub fn canvas(&self) -> &Canvas {
+        &self.canvas
+    }
 
-        v
+    /// Removes the finished rows and returns them with their marks.
+    ///
+    /// Rows from the prompt of a command whose `D` mark hasn't been received
+    /// yet are held back, so the zones of the returned canvas are complete.
+    pub fn take(&mut self) -> Canvas {
+        let mut n = self.state.cur_y.min(self.canvas.pixels.len());
+        // nothing on the alternate screen is finished
+        if self.state.normal.is_some() {
+            n = 0;
+        }
+        if let Some(y) = open_command(&self.canvas.marks) {
+            n = n.min(y);
+        }
+        // rows in the scroll region can still move
+        if self.state.top > 0 || self.state.bottom.is_some() {
+            n = n.min(self.state.top);
+        }
+        self.state.cur_y -= n;
+        self.state.cluster = self
+            .state
+            .cluster
+            .and_then(|(x, y)| Some((x, y.checked_sub(n)?)));
+        // a cursor saved in a taken row comes back on the first held one
+        if let Some(saved) = &mut self.state.saved_cursor {
+            saved.y = saved.y.saturating_sub(n);
+        }
+        self.state.top = self.state.top.saturating_sub(n);
+        self.state.bottom = self.state.bottom.map(|b| b - n);
+        self.canvas.h -= n;
+
+        let (marks, held) = std::mem::take(&mut self.canvas.marks)
+            .into_iter()
+            .partition(|m| {
+                // a `D` right after the output's last newline still closes it
+                m.y < n || (m.y == n && m.x == 0 && matches!(m.kind, PromptMark::CommandEnd(_)))
+            });
+        self.canvas.marks = held;
+        for mark in self.canvas.marks.iter_mut() {
+            mark.y -= n;
+        }
+
+        Canvas {
+            pixels: self.canvas.pixels.drain(..n).collect(),
+            w: self.canvas.w,
+            h: n,
+            palette: self.canvas.palette.clone(),
+            marks,
+            scrollback: 0,
+            alternate: None,
+        }
+    }
+
+    /// Removes and returns the finished rows.
+    pub fn take_rows(&mut self) -> Vec<Vec<Node>> {
+        self.take().pixels
+    }
+
+    /// Lexes the buffered input and returns the rows that were not taken.
+    pub fn finish(mut self) -> Result<Canvas, Error> {
+        for token in self.lexer.finish_chars() {
+            self.canvas.apply(&mut self.state, token)?;
+        }
+        self.canvas.finish(&mut self.state);
+        Ok(self.canvas)
     }
 }
 
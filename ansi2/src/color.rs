@@ -1,4 +1,4 @@
-use crate::theme::{COLOR256, ColorTable};
+use crate::theme::ColorTable;
 
 pub fn get_hex((r, g, b): (u8, u8, u8)) -> String {
     format!("#{r:02X}{g:02X}{b:02X}")
@@ -164,7 +164,7 @@ impl AnsiColor {
             AnsiColor::Default => "#00000000".into(),
             AnsiColor::Color8(n) => n.get_hex(theme),
             AnsiColor::Rgb(r, g, b) => get_hex((*r, *g, *b)),
-            AnsiColor::Color256(c) => get_hex(COLOR256[*c as usize]),
+            AnsiColor::Color256(c) => get_hex(theme.color256(*c)),
         }
     }
 
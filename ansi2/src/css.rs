@@ -25,11 +25,17 @@ pub enum NodeStyle {
     Dim,
     Italic,
     Underline,
-    Hide,
+    Conceal,
     Row,
     Text,
     Main,
     Strike,
+    Prompt,
+    Command,
+    Output,
+    Wide,
+    ReverseColor,
+    ReverseBg,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -42,8 +48,12 @@ pub struct Style {
     pub dim: bool,
     pub italic: bool,
     pub underline: bool,
-    pub hide: bool,
+    pub conceal: bool,
     pub strike: bool,
+    pub wide: bool,
+    // reversed cells whose drawn color is the default one of the other side
+    pub reverse_color: bool,
+    pub reverse_bg: bool,
 }
 
 impl NodeStyle {
@@ -54,11 +64,17 @@ impl NodeStyle {
             NodeStyle::Dim => "d",
             NodeStyle::Italic => "i",
             NodeStyle::Underline => "u",
-            NodeStyle::Hide => "h",
+            NodeStyle::Conceal => "h",
             NodeStyle::Row => "r",
             NodeStyle::Text => "t",
             NodeStyle::Main => "m",
             NodeStyle::Strike => "s",
+            NodeStyle::Prompt => "prompt",
+            NodeStyle::Command => "command",
+            NodeStyle::Output => "output",
+            NodeStyle::Wide => "w",
+            NodeStyle::ReverseColor => "rc",
+            NodeStyle::ReverseBg => "rb",
         }
     }
 }
@@ -118,8 +134,13 @@ impl Style {
                 NodeStyle::Bold.class_name()
             ));
         }
-        if self.hide {
-            css.push_str(&format!(".{}{{opacity:0}}", NodeStyle::Hide.class_name()));
+        if self.conceal {
+            // an html cell has its own background, only the text goes
+            let hidden = match ty {
+                CssType::Svg => "opacity:0",
+                CssType::Html => "color:transparent!important",
+            };
+            css.push_str(&format!(".{}{{{hidden}}}", NodeStyle::Conceal.class_name()));
         }
         if self.dim {
             css.push_str(&format!(
@@ -145,6 +166,12 @@ impl Style {
                 NodeStyle::Strike.class_name()
             ));
         }
+        if self.wide {
+            css.push_str(&format!(
+                ".{}{{width:2ch;text-align:center}}",
+                NodeStyle::Wide.class_name()
+            ));
+        }
         if self.blink {
             css.push_str(&format!(
                 ".{}{{animation:bk 1s steps(1, end) infinite;}} @keyframes bk{{50% {{opacity: 0}}}}",
@@ -152,14 +179,34 @@ impl Style {
             ));
         }
 
-        let dark_bg_color = dark_bg.unwrap_or("#181818".to_string());
-        let light_bg_color = light_bg.unwrap_or("#FFFFFF".to_string());
+        let background = theme.background().map(get_hex);
+        let dark_bg_color = dark_bg
+            .or(background.clone())
+            .unwrap_or("#181818".to_string());
+        let light_bg_color = light_bg.or(background).unwrap_or("#FFFFFF".to_string());
+        let dark_fg = get_hex(theme.foreground().unwrap_or(theme.white()));
+        let light_fg = get_hex(theme.foreground().unwrap_or(theme.black()));
 
         let (color_field, bg_field) = match ty {
             CssType::Html => ("color", "background"),
             CssType::Svg => ("fill", "fill"),
         };
 
+        let reverse_css = |fg: &str, bg: &str| {
+            let mut css = String::new();
+            if self.reverse_color {
+                let name = NodeStyle::ReverseColor.class_name();
+                css.push_str(&format!(".{name}{{{color_field}:{bg}}}"));
+            }
+            if self.reverse_bg {
+                let name = NodeStyle::ReverseBg.class_name();
+                css.push_str(&format!(".{name}{{{bg_field}:{fg}}}"));
+            }
+            css
+        };
+        let light_reverse_css = reverse_css(&light_fg, &light_bg_color);
+        let dark_reverse_css = reverse_css(&dark_fg, &dark_bg_color);
+
         let light_color_css: String = self.colors.iter().fold(String::new(), |mut acc, c| {
             acc.push_str(&format!(
                 ".{}{{{color_field}:{}}}",
@@ -210,25 +257,17 @@ impl Style {
 
         if let Some(mode) = mode {
             let default_text_style = match (mode, ty) {
-                (Mode::Dark, CssType::Html) => {
-                    format!("div{{color: {} }}", get_hex(theme.white()))
-                }
-                (Mode::Dark, CssType::Svg) => {
-                    format!("svg text{{fill:{}}}", get_hex(theme.white()))
-                }
-                (Mode::Light, CssType::Html) => {
-                    format!("div{{color:{}}}", get_hex(theme.black()))
-                }
-                (Mode::Light, CssType::Svg) => {
-                    format!("svg text{{fill:{}}}", get_hex(theme.black()))
-                }
+                (Mode::Dark, CssType::Html) => format!("div{{color: {dark_fg} }}"),
+                (Mode::Dark, CssType::Svg) => format!("svg text{{fill:{dark_fg}}}"),
+                (Mode::Light, CssType::Html) => format!("div{{color:{light_fg}}}"),
+                (Mode::Light, CssType::Svg) => format!("svg text{{fill:{light_fg}}}"),
             };
 
             css.push_str(&default_text_style);
 
-            let (color_css, bg_color_css) = match mode {
-                Mode::Dark => (dark_color_css, bg_dark_color_css),
-                Mode::Light => (light_color_css, bg_light_color_css),
+            let (color_css, bg_color_css, reverse_css) = match mode {
+                Mode::Dark => (dark_color_css, bg_dark_color_css, dark_reverse_css),
+                Mode::Light => (light_color_css, bg_light_color_css, light_reverse_css),
             };
 
             let root_style = match mode {
@@ -239,25 +278,26 @@ impl Style {
             css.push_str(&root_style);
             css.push_str(&color_css);
             css.push_str(&bg_color_css);
+            css.push_str(&reverse_css);
             return css;
         }
 
         let default_light_text_style = match ty {
-            CssType::Svg => format!("svg text{{fill:{}}}", get_hex(theme.black())),
-            CssType::Html => format!("div{{color:{}}}", get_hex(theme.black())),
+            CssType::Svg => format!("svg text{{fill:{light_fg}}}"),
+            CssType::Html => format!("div{{color:{light_fg}}}"),
         };
 
         let default_dark_text_style = match ty {
-            CssType::Svg => format!("svg text{{fill:{}}}", get_hex(theme.white())),
-            CssType::Html => format!("div{{color:{}}}", get_hex(theme.white())),
+            CssType::Svg => format!("svg text{{fill:{dark_fg}}}"),
+            CssType::Html => format!("div{{color:{dark_fg}}}"),
         };
 
         let root_css = format!(
-            r#":root {{color-scheme: light dark; background: {light_bg_color}}}{light_color_css}{bg_light_color_css}{default_light_text_style}"#
+            r#":root {{color-scheme: light dark; background: {light_bg_color}}}{light_color_css}{bg_light_color_css}{light_reverse_css}{default_light_text_style}"#
         );
 
         let dark_css = format!(
-            r#"@media (prefers-color-scheme: dark) {{:root {{background: {dark_bg_color}}}{dark_color_css}{bg_dark_color_css}{default_dark_text_style}}}"#)
+            r#"@media (prefers-color-scheme: dark) {{:root {{background: {dark_bg_color}}}{dark_color_css}{bg_dark_color_css}{dark_reverse_css}{default_dark_text_style}}}"#)
         .trim()
         .to_string();
 
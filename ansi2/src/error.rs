@@ -0,0 +1,22 @@
+use std::fmt;
+
+use crate::canvas::MAX_CELLS;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The canvas would need more than [`MAX_CELLS`] cells, usually from a
+    /// cursor movement far past the text.
+    TooLarge { w: usize, h: usize },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TooLarge { w, h } => {
+                write!(f, "canvas of {w}x{h} is larger than {MAX_CELLS} cells")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
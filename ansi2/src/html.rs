@@ -1,9 +1,174 @@
 use crate::{
-    canvas::Canvas,
+    canvas::{Canvas, Zone, ZoneKind, minify_row},
     css::{CssType, DEFAULT_FONTS, Mode, NodeStyle, Style},
-    theme::ColorTable,
+    error::Error,
+    node::Node,
+    theme::{ColorTable, Palette},
 };
 
+// (font_style, font_family)
+fn get_font(font: Option<String>) -> (String, String) {
+    let mut font_style = "".into();
+    let mut font_family = DEFAULT_FONTS.into();
+
+    if let Some(url) = font {
+        if url.starts_with("http") || url.starts_with("data:font;base64") {
+            font_family = "ansi2-custom-font".into();
+            font_style =
+                format!(r#"@font-face {{font-family: ansi2-custom-font;src: url({url});}}"#)
+        } else {
+            font_family = url;
+        }
+    }
+    (font_style, font_family)
+}
+
+fn cell_to_html(
+    c: &Node,
+    zone: Option<&Zone>,
+    wide: bool,
+    style: &mut Style,
+    sourcemap: bool,
+) -> String {
+    let mut text_class = vec![NodeStyle::Text.class_name().to_string()];
+    if wide {
+        text_class.push(NodeStyle::Wide.class_name().to_string());
+        style.wide = true;
+    }
+    if c.bold {
+        text_class.push(NodeStyle::Bold.class_name().to_string());
+        style.bold = true;
+    }
+    if c.italic {
+        text_class.push(NodeStyle::Italic.class_name().to_string());
+        style.italic = true;
+    }
+    if c.dim {
+        text_class.push(NodeStyle::Dim.class_name().to_string());
+        style.dim = true;
+    }
+    if c.underline {
+        text_class.push(NodeStyle::Underline.class_name().to_string());
+        style.underline = true;
+    }
+    if c.conceal {
+        text_class.push(NodeStyle::Conceal.class_name().to_string());
+        style.conceal = true;
+    }
+    if c.blink {
+        text_class.push(NodeStyle::Blink.class_name().to_string());
+        style.blink = true;
+    }
+    if c.strike {
+        text_class.push(NodeStyle::Strike.class_name().to_string());
+        style.strike = true;
+    }
+    let (color, bg_color) = c.draw_colors();
+    if !color.is_default() {
+        text_class.push(color.class_name());
+        style.add_color(color);
+    } else if c.reverse {
+        text_class.push(NodeStyle::ReverseColor.class_name().to_string());
+        style.reverse_color = true;
+    }
+
+    if !bg_color.is_default() {
+        text_class.push(bg_color.bg_class_name());
+        style.add_bg_color(bg_color);
+    } else if c.reverse {
+        text_class.push(NodeStyle::ReverseBg.class_name().to_string());
+        style.reverse_bg = true;
+    }
+
+    if sourcemap {
+        text_class.push(format!("text:{}:{}", c.text_r.0, c.text_r.1));
+        text_class.push(format!("color:{}:{}", c.color_r.0, c.color_r.1));
+        text_class.push(format!("bg:{}:{}", c.bg_color_r.0, c.bg_color_r.1));
+        text_class.push(format!("bold:{}:{}", c.bold_r.0, c.bold_r.1));
+        text_class.push(format!("blink:{}:{}", c.blink_r.0, c.blink_r.1));
+        text_class.push(format!("dim:{}:{}", c.dim_r.0, c.dim_r.1));
+        text_class.push(format!("italic:{}:{}", c.italic_r.0, c.italic_r.1));
+        text_class.push(format!("underline:{}:{}", c.underline_r.0, c.underline_r.1));
+        text_class.push(format!("conceal:{}:{}", c.conceal_r.0, c.conceal_r.1));
+        text_class.push(format!("strike:{}:{}", c.strike_r.0, c.strike_r.1));
+        text_class.push(format!("reverse:{}:{}", c.reverse_r.0, c.reverse_r.1));
+    }
+
+    let mut attr = String::new();
+    if let Some(zone) = zone {
+        let kind = match zone.kind {
+            ZoneKind::Prompt => NodeStyle::Prompt,
+            ZoneKind::Command => NodeStyle::Command,
+            ZoneKind::Output => NodeStyle::Output,
+        };
+        text_class.push(kind.class_name().to_string());
+        attr.push_str(&format!(" data-command='{}'", zone.command));
+        if let Some(exit) = zone.exit {
+            attr.push_str(&format!(" data-exit='{exit}'"));
+        }
+    }
+
+    let text_class = text_class.join(" ").trim().to_string();
+    let html_char = c.text.to_string();
+    let html_char = html_escape::encode_text(&html_char);
+    let class_str = if text_class.is_empty() {
+        String::new()
+    } else {
+        format!("class='{text_class}'")
+    };
+    format!("<p {class_str}{attr}>{html_char}</p>")
+}
+
+// cells of different zones are never merged into one element
+fn row_to_html(
+    row: &[Node],
+    y: usize,
+    zones: &[Zone],
+    style: &mut Style,
+    sourcemap: bool,
+) -> String {
+    let mut s = format!("<div class='{}'>", NodeStyle::Row.class_name());
+    let zones: Vec<_> = zones
+        .iter()
+        .filter(|z| z.start.0 <= y && y <= z.end.0)
+        .collect();
+    let zone_at = |x: usize| {
+        zones
+            .iter()
+            .position(|z| z.start <= (y, x) && (y, x) < z.end)
+    };
+
+    // a wide char is an element of its own that is sized to two cells
+    let wide_at = |x: usize| row.get(x + 1).is_some_and(|c| c.is_continuation());
+
+    let mut start = 0;
+    for x in 1..=row.len() {
+        let zone = zone_at(start);
+        if x < row.len() && zone_at(x) == zone && !wide_at(x) && !row[x - 1].is_continuation() {
+            continue;
+        }
+        for c in minify_row(&row[start..x]) {
+            s.push_str(&cell_to_html(
+                &c,
+                zone.map(|i| zones[i]),
+                wide_at(start),
+                style,
+                sourcemap,
+            ));
+        }
+        start = x;
+    }
+
+    if row.is_empty() {
+        s.push_str("<br>");
+    }
+    // .row
+    s.push_str("</div>");
+    s
+}
+
+const HTML_HEAD: &str = r#"<!DOCTYPE html><html lang="en"><head><meta charset="UTF-8"><meta name="viewport" content="width=device-width, initial-scale=1.0">"#;
+
 #[allow(clippy::too_many_arguments)]
 pub fn to_html<S: AsRef<str>>(
     str: S,
@@ -15,110 +180,63 @@ pub fn to_html<S: AsRef<str>>(
     dark_bg: Option<String>,
     font_size: Option<usize>,
     sourcemap: bool,
+) -> String {
+    let canvas = Canvas::new(str, width);
+    canvas_to_html(
+        &canvas, theme, font, mode, light_bg, dark_bg, font_size, sourcemap,
+    )
+}
+
+/// Like [`to_html`], but fails on input that would need a huge canvas.
+#[allow(clippy::too_many_arguments)]
+pub fn try_to_html<S: AsRef<str>>(
+    str: S,
+    theme: impl ColorTable,
+    width: Option<usize>,
+    font: Option<String>,
+    mode: Option<Mode>,
+    light_bg: Option<String>,
+    dark_bg: Option<String>,
+    font_size: Option<usize>,
+    sourcemap: bool,
+) -> Result<String, Error> {
+    let canvas = Canvas::try_new(str, width)?;
+    Ok(canvas_to_html(
+        &canvas, theme, font, mode, light_bg, dark_bg, font_size, sourcemap,
+    ))
+}
+
+/// Renders a canvas, e.g. one cropped with [`Canvas::view`].
+#[allow(clippy::too_many_arguments)]
+pub fn canvas_to_html(
+    canvas: &Canvas,
+    theme: impl ColorTable,
+    font: Option<String>,
+    mode: Option<Mode>,
+    light_bg: Option<String>,
+    dark_bg: Option<String>,
+    font_size: Option<usize>,
+    sourcemap: bool,
 ) -> String {
     let font_size = font_size.unwrap_or(16);
-    let s = str.as_ref();
-    let canvas = Canvas::new(s, width);
     let mut s = String::new();
 
     let mut style = Style::default();
 
-    let mut font_style = "".into();
-    let mut font_family = DEFAULT_FONTS.into();
-
-    if let Some(url) = font {
-        if url.starts_with("http") || url.starts_with("data:font;base64") {
-            font_family = "ansi2-custom-font".into();
-            font_style =
-                format!(r#"@font-face {{font-family: ansi2-custom-font;src: url({url});}}"#)
-        } else {
-            font_family = url;
-        }
-    }
+    let (font_style, font_family) = get_font(font);
 
     s.push_str(&format!("<div class='{}'>", NodeStyle::Main.class_name()));
 
-    let row_style = format!("<div class='{}'>", NodeStyle::Row.class_name());
-    for row in canvas.minify().iter() {
-        s.push_str(&row_style);
-        for c in row.iter() {
-            let mut text_class = vec![NodeStyle::Text.class_name().to_string()];
-            if c.bold {
-                text_class.push(NodeStyle::Bold.class_name().to_string());
-                style.bold = true;
-            }
-            if c.italic {
-                text_class.push(NodeStyle::Italic.class_name().to_string());
-                style.italic = true;
-            }
-            if c.dim {
-                text_class.push(NodeStyle::Dim.class_name().to_string());
-                style.dim = true;
-            }
-            if c.underline {
-                text_class.push(NodeStyle::Underline.class_name().to_string());
-                style.underline = true;
-            }
-            if c.hide {
-                text_class.push(NodeStyle::Hide.class_name().to_string());
-                style.hide = true;
-            }
-            if c.blink {
-                text_class.push(NodeStyle::Blink.class_name().to_string());
-                style.blink = true;
-            }
-            if c.strike {
-                text_class.push(NodeStyle::Strike.class_name().to_string());
-                style.strike = true;
-            }
-            if !c.color.is_default() {
-                let name = c.color.class_name();
-                text_class.push(name);
-                style.add_color(c.color);
-            }
-
-            if !c.bg_color.is_default() {
-                let name = c.bg_color.bg_class_name();
-                text_class.push(name);
-                style.add_bg_color(c.bg_color);
-            }
-
-            if sourcemap {
-                text_class.push(format!("text:{}:{}", c.text_r.0, c.text_r.1));
-                text_class.push(format!("color:{}:{}", c.color_r.0, c.color_r.1));
-                text_class.push(format!("bg:{}:{}", c.bg_color_r.0, c.bg_color_r.1));
-                text_class.push(format!("bold:{}:{}", c.bold_r.0, c.bold_r.1));
-                text_class.push(format!("blink:{}:{}", c.blink_r.0, c.blink_r.1));
-                text_class.push(format!("dim:{}:{}", c.dim_r.0, c.dim_r.1));
-                text_class.push(format!("italic:{}:{}", c.italic_r.0, c.italic_r.1));
-                text_class.push(format!("underline:{}:{}", c.underline_r.0, c.underline_r.1));
-                text_class.push(format!("hide:{}:{}", c.hide_r.0, c.hide_r.1));
-                text_class.push(format!("strike:{}:{}", c.strike_r.0, c.strike_r.1));
-            }
-
-            let text_class = text_class.join(" ").trim().to_string();
-            let html_char = c.text.to_string();
-            let html_char = html_escape::encode_text(&html_char);
-            let class_str = if text_class.is_empty() {
-                String::new()
-            } else {
-                format!("class='{text_class}'")
-            };
-            s.push_str(&format!("<p {class_str}>{html_char}</p>",))
-        }
-
-        if row.is_empty() {
-            s.push_str("<br>");
-        }
-        // .row
-        s.push_str("</div>");
+    let zones = canvas.zones();
+    for (y, row) in canvas.pixels.iter().enumerate() {
+        s.push_str(&row_to_html(row, y, &zones, &mut style, sourcemap));
     }
 
     // .ansi-main
     s.push_str("</div>");
 
     let style_css = style.to_css(
-        theme,
+        canvas.palette.over(theme),
         CssType::Html,
         mode,
         light_bg,
@@ -126,7 +244,86 @@ pub fn to_html<S: AsRef<str>>(
         font_family,
         font_size,
     );
-    format!(
-        r#"<!DOCTYPE html><html lang="en"><head><meta charset="UTF-8"><meta name="viewport" content="width=device-width, initial-scale=1.0"><style>{font_style}{style_css}</style></head><body>{s}</body></html>"#
-    )
+    format!(r#"{HTML_HEAD}<style>{font_style}{style_css}</style></head><body>{s}</body></html>"#)
+}
+
+/// Writes the rows taken from a [`crate::canvas::CanvasStream`] as html.
+///
+/// The css depends on every row, so it is written by [`HtmlStream::finish`]
+/// at the end of the body instead of in the head.
+#[derive(Debug, Clone)]
+pub struct HtmlStream<T: ColorTable> {
+    theme: T,
+    font: Option<String>,
+    mode: Option<Mode>,
+    light_bg: Option<String>,
+    dark_bg: Option<String>,
+    font_size: usize,
+    sourcemap: bool,
+    style: Style,
+}
+
+impl<T: ColorTable> HtmlStream<T> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        theme: T,
+        font: Option<String>,
+        mode: Option<Mode>,
+        light_bg: Option<String>,
+        dark_bg: Option<String>,
+        font_size: Option<usize>,
+        sourcemap: bool,
+    ) -> Self {
+        HtmlStream {
+            theme,
+            font,
+            mode,
+            light_bg,
+            dark_bg,
+            font_size: font_size.unwrap_or(16),
+            sourcemap,
+            style: Style::default(),
+        }
+    }
+
+    pub fn head(&self) -> String {
+        format!(
+            "{HTML_HEAD}</head><body><div class='{}'>",
+            NodeStyle::Main.class_name()
+        )
+    }
+
+    pub fn write_rows(&mut self, rows: &[Vec<Node>]) -> String {
+        rows.iter()
+            .enumerate()
+            .map(|(y, row)| row_to_html(row, y, &[], &mut self.style, self.sourcemap))
+            .collect()
+    }
+
+    /// Like [`HtmlStream::write_rows`], but also marks the zones of the
+    /// canvas taken by [`crate::canvas::CanvasStream::take`].
+    pub fn write_canvas(&mut self, canvas: &Canvas) -> String {
+        let zones = canvas.zones();
+        canvas
+            .pixels
+            .iter()
+            .enumerate()
+            .map(|(y, row)| row_to_html(row, y, &zones, &mut self.style, self.sourcemap))
+            .collect()
+    }
+
+    /// `palette` is the one of the finished [`crate::canvas::CanvasStream`].
+    pub fn finish(self, palette: &Palette) -> String {
+        let (font_style, font_family) = get_font(self.font);
+        let style_css = self.style.to_css(
+            palette.over(self.theme),
+            CssType::Html,
+            self.mode,
+            self.light_bg,
+            self.dark_bg,
+            font_family,
+            self.font_size,
+        );
+        format!("</div><style>{font_style}{style_css}</style></body></html>")
+    }
 }
@@ -1,462 +1,1136 @@
-use crate::color::{AnsiColor, Color8};
+use crate::color::AnsiColor;
+use crate::width::graphemes;
 use nom::IResult;
 use nom::Parser;
 use nom::branch::alt;
-use nom::bytes::complete::{tag, tag_no_case, take_until, take_while1};
-use nom::character::complete::{anychar, digit0};
+use nom::bytes::complete::{tag, take_till, take_until, take_while};
+use nom::character::complete::{anychar, digit0, digit1, one_of, satisfy};
 use nom::combinator::opt;
-use nom::multi::many0;
+use std::collections::VecDeque;
 
-#[derive(Debug, Clone)]
+/// A lexed control function or printable character.
+///
+/// Numeric arguments are kept as written, missing ones take the default of
+/// the sequence.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Sgr {
+    /// A printable character.
     Char(char),
+    /// A grapheme cluster of more than one character, e.g. a letter with
+    /// combining accents or a ZWJ emoji sequence.
+    Grapheme(String),
 
+    /// `BEL`
     Bell,
+    /// `BS`
     Backspace,
+    /// `HT`
     Tab,
+    /// `LF`
     LineFeed,
+    /// `FF`
     FormFeed,
+    /// `CR`
     CarriageReturn,
-    Title(String),
+    /// `OSC 0`, `OSC 1` or `OSC 2` and the title, 1 sets the icon name, 2 the
+    /// window title and 0 both of them.
+    Title(u8, String),
+    /// `OSC 7`, the working directory url.
     Cwd(String),
-    Prompt,
+    /// `OSC 4`, redefines a palette color.
+    SetPalette(u8, (u8, u8, u8)),
+    /// `OSC 104`, resets the given palette colors, or all of them if empty.
+    ResetPalette(Vec<u8>),
+    /// `OSC 10`
+    SetDefaultForeground((u8, u8, u8)),
+    /// `OSC 11`
+    SetDefaultBackground((u8, u8, u8)),
+    /// `OSC 110`
+    ResetDefaultForeground,
+    /// `OSC 111`
+    ResetDefaultBackground,
+    /// `OSC 133`, a shell integration mark.
+    Prompt(PromptMark),
+    /// `CSI n A`
     CursorUp(i32),
+    /// `CSI n B`
     CursorDown(i32),
+    /// `CSI n C`
     CursorForward(i32),
+    /// `CSI n D`
     CursorBack(i32),
+    /// `CSI n E`
     CursorNextLine(i32),
+    /// `CSI n F`
     CursorPreviousLine(i32),
+    /// `CSI n G`
     CursorHorizontalAbsolute(i32),
+    /// `CSI row ; col H`, 1-based.
     CursorPosition(i32, i32),
+    /// `CSI n J`
     EraseInDisplay(i32),
+    /// `CSI n K`
     EraseInLine(i32),
+    /// `ESC H`, HTS, sets a tab stop at the cursor column.
+    TabSet,
+    /// `CSI n g`, TBC, 0 clears the tab stop at the cursor column and 3
+    /// clears all of them.
+    TabClear(i32),
+    /// `CSI n I`, CHT, moves forward `n` tab stops.
+    CursorForwardTab(i32),
+    /// `CSI n Z`, CBT, moves back `n` tab stops.
+    CursorBackwardTab(i32),
+    /// `CSI n @`, inserts `n` blanks at the cursor.
+    InsertCharacter(i32),
+    /// `CSI n P`, deletes `n` chars at the cursor.
+    DeleteCharacter(i32),
+    /// `CSI n X`, erases `n` chars from the cursor on.
+    EraseCharacter(i32),
+    /// `CSI n L`, inserts `n` blank lines at the cursor row.
+    InsertLine(i32),
+    /// `CSI n M`, deletes `n` lines from the cursor row on.
+    DeleteLine(i32),
+    /// `CSI n S`
     ScrollUp(i32),
+    /// `CSI n T`
     ScrollDown(i32),
+    /// `CSI top ; bottom r`, 1-based, a bottom of 0 is the last row.
+    SetScrollRegion(i32, i32),
+    /// `ESC M`, moves up and scrolls down at the top margin.
+    ReverseIndex,
+    /// `CSI row ; col f`, the same as [`Sgr::CursorPosition`].
     HorizontalVerticalPosition(i32, i32),
+    /// `CSI n d`, moves to row `n`.
+    LinePositionAbsolute(i32),
+    /// `` CSI n ` ``, the same as [`Sgr::CursorHorizontalAbsolute`].
+    CharacterPositionAbsolute(i32),
+    /// `CSI n b`, prints the preceding char `n` more times.
+    Repeat(i32),
+    /// `ESC 5 i`
     AUXPortOn,
+    /// `CSI 4 i`
     AUXPortOff,
+    /// `CSI 6 n`
     DeviceStatusReport,
 
+    /// `CSI s`, saves the cursor position and attributes.
     CursorSave,
+    /// `CSI u`, restores what [`Sgr::CursorSave`] saved.
     CursorRestore,
-
-    CursorHide,
-    CursorShow,
+    /// `ESC 7`, DECSC, the same as [`Sgr::CursorSave`].
+    DecSaveCursor,
+    /// `ESC 8`, DECRC, the same as [`Sgr::CursorRestore`].
+    DecRestoreCursor,
+
+    /// `SGR 8`, the text is invisible but its background is drawn
+    Conceal,
+    /// `SGR 30-38`, `SGR 90-97`
     ColorForeground(AnsiColor),
+    /// `SGR 40-48`, `SGR 100-107`
     ColorBackground(AnsiColor),
+    /// `SGR 58`
     ColorUnderLine(AnsiColor),
     ColorFgBg(AnsiColor, AnsiColor),
 
+    /// `SGR 0`
     ColorReset,
+    /// `SGR 7`
     Reverse,
+    /// `SGR 39`
     ColorDefaultForeground,
+    /// `SGR 49`
     ColorDefaultBackground,
+    /// `SGR 59`
     ColorDefaultUnderline,
 
+    /// `SGR 1`
     Bold,
+    /// `SGR 22`
     NormalIntensity,
+    /// `SGR 3`
     Italic,
+    /// `SGR 4`
     Underline,
+    /// `SGR 2`
     Dim,
+    /// `SGR 5`
     SlowBlink,
+    /// `SGR 6`
     RapidBlink,
+    /// `SGR 9`
     Strike,
+    /// `SGR 29`
     UnStrike,
+    /// `SGR 23`
     UnItalic,
+    /// `SGR 10`
     PrimaryFont,
+    /// `SGR 21`
     DoublyUnderlined,
+    // \x1b[4:3m, 2: double, 3: curly, 4: dotted, 5: dashed
+    UnderlineStyle(u8),
+    /// `SGR 24`
     UnUnderlined,
+    /// `SGR 25`
     UnBlink,
-    Decset,
-    Decrst,
+    /// `CSI ? n ; ... h`, DECSET
+    Decset(Vec<DecMode>),
+    /// `CSI ? n ; ... l`, DECRST
+    Decrst(Vec<DecMode>),
+    /// `CSI n ; ... h`, SM, ANSI modes are ignored
+    SetMode(Vec<u16>),
+    /// `CSI n ; ... l`, RM
+    ResetMode(Vec<u16>),
+    /// `ESC =`
     Keypad,
+    /// `ESC ( c`, designates a character set into G0.
+    DesignateG0(Charset),
+    /// `ESC ) c`, designates a character set into G1.
+    DesignateG1(Charset),
+    /// `SO`, invokes G1.
+    ShiftOut,
+    /// `SI`, invokes G0 again.
+    ShiftIn,
 
     // \x1b[>4;1m
     Ext,
 
+    /// Several attributes set by a single SGR sequence.
     List(Vec<Sgr>),
 
     // url, title
     Link(String, String),
 
+    /// `SGR 11-19`
     AlternativeFont(u8),
+    /// `SGR 20`
     Fraktur,
+    /// `SGR 27`
     UnReversed,
-    UnHide,
-    Unknown(u8),
+    /// `SGR 28`
+    Reveal,
+    /// An SGR parameter without a meaning here.
+    UnknownSgr(u16),
+    /// A C0 or C1 control character without a meaning here.
+    Control(char),
+    /// Any other well-formed CSI sequence, kept as written and ignored.
+    UnknownCsi(String),
 }
 
-#[derive(Debug, Clone)]
+/// The kind of an `OSC 133` mark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptMark {
+    /// `A`
+    PromptStart,
+    /// `B`
+    CommandStart,
+    /// `C`
+    OutputStart,
+    /// `D`, with the exit code if the shell reports it.
+    CommandEnd(Option<i32>),
+    /// Any other mark, e.g. kitty's `P`.
+    Other(char),
+}
+
+/// A DEC private mode, set by [`Sgr::Decset`] and reset by [`Sgr::Decrst`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DecMode {
+    /// `1`, DECCKM
+    CursorKeys,
+    /// `6`, DECOM, cursor addressing is relative to the scroll region.
+    Origin,
+    /// `7`, DECAWM, set by default.
+    Autowrap,
+    /// `25`, DECTCEM, set by default.
+    CursorVisible,
+    /// `47`
+    AlternateScreen,
+    /// `1047`, also clears the alternate screen when it is left.
+    AlternateScreenClear,
+    /// `1049`, also saves the cursor and clears the alternate screen.
+    AlternateScreenSaveCursor,
+    /// `2004`
+    BracketedPaste,
+    /// Any other mode, tracked but not acted on.
+    Other(u16),
+}
+
+impl DecMode {
+    pub fn from_u16(n: u16) -> Self {
+        match n {
+            1 => DecMode::CursorKeys,
+            6 => DecMode::Origin,
+            7 => DecMode::Autowrap,
+            25 => DecMode::CursorVisible,
+            47 => DecMode::AlternateScreen,
+            1047 => DecMode::AlternateScreenClear,
+            1049 => DecMode::AlternateScreenSaveCursor,
+            2004 => DecMode::BracketedPaste,
+            n => DecMode::Other(n),
+        }
+    }
+
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            DecMode::CursorKeys => 1,
+            DecMode::Origin => 6,
+            DecMode::Autowrap => 7,
+            DecMode::CursorVisible => 25,
+            DecMode::AlternateScreen => 47,
+            DecMode::AlternateScreenClear => 1047,
+            DecMode::AlternateScreenSaveCursor => 1049,
+            DecMode::BracketedPaste => 2004,
+            DecMode::Other(n) => *n,
+        }
+    }
+
+    /// The state of the mode before the input changes it.
+    pub fn default_value(&self) -> bool {
+        matches!(self, DecMode::Autowrap | DecMode::CursorVisible)
+    }
+}
+
+/// A character set designated by `ESC ( c` or `ESC ) c`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Charset {
+    /// `B`
+    #[default]
+    Ascii,
+    /// `0`, the DEC Special Graphics set used for line drawing.
+    DecSpecialGraphics,
+    /// `A`
+    Uk,
+    /// Any other set, printed as ASCII.
+    Other(char),
+}
+
+// '_' to '~' in the DEC Special Graphics set
+const DEC_SPECIAL_GRAPHICS: [char; 32] = [
+    ' ', '◆', '▒', '␉', '␌', '␍', '␊', '°', '±', '␤', '␋', '┘', '┐', '┌', '└', '┼', '⎺', '⎻', '─',
+    '⎼', '⎽', '├', '┤', '┴', '┬', '│', '≤', '≥', 'π', '≠', '£', '·',
+];
+
+impl Charset {
+    fn from_final(c: char) -> Self {
+        match c {
+            'B' => Charset::Ascii,
+            '0' => Charset::DecSpecialGraphics,
+            'A' => Charset::Uk,
+            c => Charset::Other(c),
+        }
+    }
+
+    fn final_char(&self) -> char {
+        match self {
+            Charset::Ascii => 'B',
+            Charset::DecSpecialGraphics => '0',
+            Charset::Uk => 'A',
+            Charset::Other(c) => *c,
+        }
+    }
+
+    /// Maps a char printed while the set is invoked to its Unicode glyph.
+    pub fn map(&self, c: char) -> char {
+        match (self, c) {
+            (Charset::DecSpecialGraphics, '_'..='~') => {
+                DEC_SPECIAL_GRAPHICS[c as usize - '_' as usize]
+            }
+            (Charset::Uk, '#') => '£',
+            _ => c,
+        }
+    }
+}
+
+/// A [`Sgr`] and the byte range of the input it was lexed from.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Token {
     pub sgr: Sgr,
     pub range: (usize, usize),
 }
 
-fn get_sgr_color(n: u8) -> Sgr {
-    match n {
-        39 => Sgr::ColorDefaultForeground,
-        49 => Sgr::ColorDefaultBackground,
-        30..=37 | 90..=97 => Sgr::ColorForeground(AnsiColor::from_u8(n)),
-        40..=47 | 100..=107 => Sgr::ColorBackground(AnsiColor::from_u8(n)),
-        _ => Sgr::Unknown(0),
+fn color_spec((r, g, b): (u8, u8, u8)) -> String {
+    format!("rgb:{r:02x}/{g:02x}/{b:02x}")
+}
+
+// `base` is 30 for the foreground, 40 for the background and 50 for underlines
+fn color_param(color: &AnsiColor, base: u8) -> String {
+    match color {
+        AnsiColor::Default => format!("{}", base + 9),
+        AnsiColor::Color8(c) if base != 50 => format!("{}", c.to_u8() + base - 30),
+        AnsiColor::Color8(c) => match c.to_u8() {
+            n @ 30..=37 => format!("58;5;{}", n - 30),
+            n => format!("58;5;{}", n - 82),
+        },
+        AnsiColor::Color256(n) => format!("{};5;{n}", base + 8),
+        AnsiColor::Rgb(r, g, b) => format!("{};2;{r};{g};{b}", base + 8),
     }
 }
 
+fn join_params(v: &[u16]) -> String {
+    v.iter()
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+fn mode_params(v: &[DecMode]) -> String {
+    join_params(&v.iter().map(|m| m.to_u16()).collect::<Vec<_>>())
+}
+
+impl Sgr {
+    // the SGR parameters of an attribute, None if it isn't set by SGR
+    fn sgr_params(&self) -> Option<String> {
+        let s = match self {
+            Sgr::ColorReset => "0".to_string(),
+            Sgr::Bold => "1".to_string(),
+            Sgr::Dim => "2".to_string(),
+            Sgr::Italic => "3".to_string(),
+            Sgr::Underline => "4".to_string(),
+            Sgr::UnderlineStyle(n) => format!("4:{n}"),
+            Sgr::SlowBlink => "5".to_string(),
+            Sgr::RapidBlink => "6".to_string(),
+            Sgr::Reverse => "7".to_string(),
+            Sgr::Conceal => "8".to_string(),
+            Sgr::Strike => "9".to_string(),
+            Sgr::PrimaryFont => "10".to_string(),
+            Sgr::AlternativeFont(n) => format!("{}", 10 + *n as u16),
+            Sgr::Fraktur => "20".to_string(),
+            Sgr::DoublyUnderlined => "21".to_string(),
+            Sgr::NormalIntensity => "22".to_string(),
+            Sgr::UnItalic => "23".to_string(),
+            Sgr::UnUnderlined => "24".to_string(),
+            Sgr::UnBlink => "25".to_string(),
+            Sgr::UnReversed => "27".to_string(),
+            Sgr::Reveal => "28".to_string(),
+            Sgr::UnStrike => "29".to_string(),
+            Sgr::ColorForeground(c) => color_param(c, 30),
+            Sgr::ColorBackground(c) => color_param(c, 40),
+            Sgr::ColorUnderLine(c) => color_param(c, 50),
+            Sgr::ColorDefaultForeground => "39".to_string(),
+            Sgr::ColorDefaultBackground => "49".to_string(),
+            Sgr::ColorDefaultUnderline => "59".to_string(),
+            Sgr::ColorFgBg(fg, bg) => format!("{};{}", color_param(fg, 30), color_param(bg, 40)),
+            Sgr::UnknownSgr(n) => n.to_string(),
+            Sgr::List(v) => v
+                .iter()
+                .map(|i| i.sgr_params())
+                .collect::<Option<Vec<_>>>()?
+                .join(";"),
+            _ => return None,
+        };
+        Some(s)
+    }
+
+    /// Encodes back to the escape sequence it is lexed from.
+    pub fn to_ans(&self) -> String {
+        match self {
+            Sgr::Char(c) => c.to_string(),
+            Sgr::Grapheme(s) => s.clone(),
+            Sgr::Bell => "\x07".to_string(),
+            Sgr::Backspace => "\x08".to_string(),
+            Sgr::Tab => "\t".to_string(),
+            Sgr::LineFeed => "\n".to_string(),
+            Sgr::FormFeed => "\x0c".to_string(),
+            Sgr::CarriageReturn => "\r".to_string(),
+            Sgr::Title(n, s) => format!("\x1b]{n};{s}\x07"),
+            Sgr::Cwd(s) => format!("\x1b]7;{s}\x07"),
+            Sgr::SetPalette(n, rgb) => format!("\x1b]4;{n};{}\x1b\\", color_spec(*rgb)),
+            Sgr::ResetPalette(v) => {
+                let args: String = v.iter().map(|n| format!(";{n}")).collect();
+                format!("\x1b]104{args}\x1b\\")
+            }
+            Sgr::SetDefaultForeground(rgb) => format!("\x1b]10;{}\x1b\\", color_spec(*rgb)),
+            Sgr::SetDefaultBackground(rgb) => format!("\x1b]11;{}\x1b\\", color_spec(*rgb)),
+            Sgr::ResetDefaultForeground => "\x1b]110\x1b\\".to_string(),
+            Sgr::ResetDefaultBackground => "\x1b]111\x1b\\".to_string(),
+            Sgr::Prompt(mark) => match mark {
+                PromptMark::PromptStart => "\x1b]133;A\x07".to_string(),
+                PromptMark::CommandStart => "\x1b]133;B\x07".to_string(),
+                PromptMark::OutputStart => "\x1b]133;C\x07".to_string(),
+                PromptMark::CommandEnd(Some(n)) => format!("\x1b]133;D;{n}\x07"),
+                PromptMark::CommandEnd(None) => "\x1b]133;D\x07".to_string(),
+                PromptMark::Other(c) => format!("\x1b]133;{c}\x07"),
+            },
+            Sgr::CursorUp(n) => format!("\x1b[{n}A"),
+            Sgr::CursorDown(n) => format!("\x1b[{n}B"),
+            Sgr::CursorForward(n) => format!("\x1b[{n}C"),
+            Sgr::CursorBack(n) => format!("\x1b[{n}D"),
+            Sgr::CursorNextLine(n) => format!("\x1b[{n}E"),
+            Sgr::CursorPreviousLine(n) => format!("\x1b[{n}F"),
+            Sgr::CursorHorizontalAbsolute(n) => format!("\x1b[{n}G"),
+            Sgr::CursorPosition(row, col) => format!("\x1b[{row};{col}H"),
+            Sgr::EraseInDisplay(n) => format!("\x1b[{n}J"),
+            Sgr::EraseInLine(n) => format!("\x1b[{n}K"),
+            Sgr::TabSet => "\x1bH".to_string(),
+            Sgr::TabClear(n) => format!("\x1b[{n}g"),
+            Sgr::CursorForwardTab(n) => format!("\x1b[{n}I"),
+            Sgr::CursorBackwardTab(n) => format!("\x1b[{n}Z"),
+            Sgr::InsertCharacter(n) => format!("\x1b[{n}@"),
+            Sgr::DeleteCharacter(n) => format!("\x1b[{n}P"),
+            Sgr::EraseCharacter(n) => format!("\x1b[{n}X"),
+            Sgr::InsertLine(n) => format!("\x1b[{n}L"),
+            Sgr::DeleteLine(n) => format!("\x1b[{n}M"),
+            Sgr::ScrollUp(n) => format!("\x1b[{n}S"),
+            Sgr::ScrollDown(n) => format!("\x1b[{n}T"),
+            Sgr::SetScrollRegion(top, bottom) => format!("\x1b[{top};{bottom}r"),
+            Sgr::ReverseIndex => "\x1bM".to_string(),
+            Sgr::HorizontalVerticalPosition(row, col) => format!("\x1b[{row};{col}f"),
+            Sgr::LinePositionAbsolute(n) => format!("\x1b[{n}d"),
+            Sgr::CharacterPositionAbsolute(n) => format!("\x1b[{n}`"),
+            Sgr::Repeat(n) => format!("\x1b[{n}b"),
+            Sgr::AUXPortOn => "\x1b[5i".to_string(),
+            Sgr::AUXPortOff => "\x1b[4i".to_string(),
+            Sgr::DeviceStatusReport => "\x1b[6n".to_string(),
+            Sgr::CursorSave => "\x1b[s".to_string(),
+            Sgr::CursorRestore => "\x1b[u".to_string(),
+            Sgr::DecSaveCursor => "\x1b7".to_string(),
+            Sgr::DecRestoreCursor => "\x1b8".to_string(),
+            Sgr::Decset(v) => format!("\x1b[?{}h", mode_params(v)),
+            Sgr::Decrst(v) => format!("\x1b[?{}l", mode_params(v)),
+            Sgr::SetMode(v) => format!("\x1b[{}h", join_params(v)),
+            Sgr::ResetMode(v) => format!("\x1b[{}l", join_params(v)),
+            Sgr::Keypad => "\x1b=".to_string(),
+            Sgr::DesignateG0(c) => format!("\x1b({}", c.final_char()),
+            Sgr::DesignateG1(c) => format!("\x1b){}", c.final_char()),
+            Sgr::ShiftOut => "\x0e".to_string(),
+            Sgr::ShiftIn => "\x0f".to_string(),
+            Sgr::Ext => "\x1b[>4;1m".to_string(),
+            Sgr::Link(url, title) => format!("\x1b]8;;{url}\x1b\\{title}\x1b]8;;\x1b\\"),
+            Sgr::Control(c) => c.to_string(),
+            Sgr::UnknownCsi(s) => s.clone(),
+            // several palette colors set by one OSC 4
+            Sgr::List(v) if !v.is_empty() && v.iter().all(|i| matches!(i, Sgr::SetPalette(..))) => {
+                let args: String = v
+                    .iter()
+                    .filter_map(|i| match i {
+                        Sgr::SetPalette(n, rgb) => Some(format!(";{n};{}", color_spec(*rgb))),
+                        _ => None,
+                    })
+                    .collect();
+                format!("\x1b]4{args}\x1b\\")
+            }
+            Sgr::List(v) if v.is_empty() || self.sgr_params().is_none() => {
+                v.iter().map(|i| i.to_ans()).collect()
+            }
+            sgr => format!("\x1b[{}m", sgr.sgr_params().unwrap_or_default()),
+        }
+    }
+}
+
+// 7-bit `ESC [` or 8-bit `CSI`
+fn csi(input: &str) -> IResult<&str, &str> {
+    alt((tag("\x1b["), tag("\u{9b}"))).parse(input)
+}
+
+// 7-bit `ESC ]` or 8-bit `OSC`
+fn osc(input: &str) -> IResult<&str, &str> {
+    alt((tag("\x1b]"), tag("\u{9d}"))).parse(input)
+}
+
+// string terminator of an OSC, `BEL`, `ESC \` or 8-bit `ST`
+fn st(input: &str) -> IResult<&str, &str> {
+    alt((tag("\x07"), tag("\x1b\\"), tag("\u{9c}"))).parse(input)
+}
+
+fn is_st(c: char) -> bool {
+    matches!(c, '\x07' | '\x1b' | '\u{9c}')
+}
+
 fn parse_cursor_up(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b["), digit0, tag_no_case("a")).parse(input)?;
+    let (rem, (_, b, _)) = (csi, digit0, tag("A")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::CursorUp(str::parse(b).unwrap_or(1)),
         },
     ))
 }
 
 fn parse_cursor_down(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b["), digit0, tag_no_case("b")).parse(input)?;
+    let (rem, (_, b, _)) = (csi, digit0, tag("B")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::CursorDown(str::parse(b).unwrap_or(1)),
         },
     ))
 }
 
 fn parse_cursor_forward(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b["), digit0, tag_no_case("c")).parse(input)?;
+    // lowercase `c` is DA, a device attributes request
+    let (rem, (_, b, _)) = (csi, digit0, tag("C")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::CursorForward(str::parse(b).unwrap_or(1)),
         },
     ))
 }
 
-fn parse_decset(input: &str) -> IResult<&str, Token> {
-    let (rem, _s) = tag("\x1b[?2004h").parse(input)?;
-    Ok((
-        rem,
-        Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::Decset,
-        },
-    ))
-}
-fn parse_decrst(input: &str) -> IResult<&str, Token> {
-    let (rem, _) = tag("\x1b[?20041").parse(input)?;
+// DECSET and DECRST with a `?`, SM and RM without
+fn parse_mode(input: &str) -> IResult<&str, Token> {
+    let (rem, csi) = parse_csi(input)?;
+    if !matches!(csi.action, 'h' | 'l')
+        || !matches!(csi.private, None | Some('?'))
+        || !csi.intermediates.is_empty()
+    {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Tag,
+        )));
+    }
+
+    let params: Vec<u16> = csi.params.iter().filter_map(|p| p[0]).collect();
+    let sgr = match (csi.private, csi.action) {
+        (Some(_), 'h') => Sgr::Decset(params.into_iter().map(DecMode::from_u16).collect()),
+        (Some(_), _) => Sgr::Decrst(params.into_iter().map(DecMode::from_u16).collect()),
+        (None, 'h') => Sgr::SetMode(params),
+        (None, _) => Sgr::ResetMode(params),
+    };
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::Decrst,
+            range: (input.len(), rem.len()),
+            sgr,
         },
     ))
 }
+
 fn parse_keypad(input: &str) -> IResult<&str, Token> {
     let (rem, _) = tag("\x1b=").parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::Keypad,
         },
     ))
 }
 fn parse_ext(input: &str) -> IResult<&str, Token> {
-    let (rem, _) = tag("\x1b[>4;1m").parse(input)?;
+    let (rem, _) = (csi, tag(">4;1m")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::Ext,
         },
     ))
 }
 fn parse_cursor_back(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b["), digit0, tag_no_case("d")).parse(input)?;
+    let (rem, (_, b, _)) = (csi, digit0, tag("D")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::CursorBack(str::parse(b).unwrap_or(1)),
         },
     ))
 }
 
 fn parse_cursor_next_line(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b["), digit0, tag_no_case("e")).parse(input)?;
+    let (rem, (_, b, _)) = (csi, digit0, tag("E")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::CursorNextLine(str::parse(b).unwrap_or(1)),
         },
     ))
 }
 
 fn parse_cursor_previous_line(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b["), digit0, tag_no_case("f")).parse(input)?;
+    let (rem, (_, b, _)) = (csi, digit0, tag("F")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::CursorPreviousLine(str::parse(b).unwrap_or(1)),
         },
     ))
 }
 
 fn parse_cursor_horizontal(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b["), digit0, tag_no_case("g")).parse(input)?;
+    let (rem, (_, b, _)) = (csi, digit0, tag("G")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::CursorHorizontalAbsolute(str::parse(b).unwrap_or(1)),
         },
     ))
 }
 
+// row and column of CUP and HVP, both default to 1
+fn parse_row_col(input: &str) -> IResult<&str, (i32, i32)> {
+    let (rem, (_, row, col)) = (csi, digit0, opt((tag(";"), digit0))).parse(input)?;
+    let col = col.map_or("", |(_, col)| col);
+    Ok((
+        rem,
+        (str::parse(row).unwrap_or(1), str::parse(col).unwrap_or(1)),
+    ))
+}
+
 fn parse_cursor_position(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, x, _, y, _)) =
-        (tag("\x1b["), digit0, tag(":"), digit0, tag_no_case("h")).parse(input)?;
+    let (rem, ((row, col), _)) = (parse_row_col, tag("H")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::CursorPosition(str::parse(x).unwrap_or(0), str::parse(y).unwrap_or(0)),
+            range: (input.len(), rem.len()),
+            sgr: Sgr::CursorPosition(row, col),
         },
     ))
 }
 
 fn parse_erase_in_display(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b["), digit0, tag_no_case("j")).parse(input)?;
+    let (rem, (_, b, _)) = (csi, digit0, tag("J")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::EraseInDisplay(str::parse(b).unwrap_or(0)),
         },
     ))
 }
 
 fn parse_erase_in_line(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b["), digit0, tag_no_case("k")).parse(input)?;
+    let (rem, (_, b, _)) = (csi, digit0, tag("K")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::EraseInLine(str::parse(b).unwrap_or(0)),
         },
     ))
 }
 
+// 7-bit `ESC H` or 8-bit `HTS`
+fn parse_tab_set(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = alt((tag("\x1bH"), tag("\u{88}"))).parse(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::TabSet,
+        },
+    ))
+}
+
+fn parse_tab_clear(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, b, _)) = (csi, digit0, tag("g")).parse(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::TabClear(str::parse(b).unwrap_or(0)),
+        },
+    ))
+}
+
+fn parse_cursor_forward_tab(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, b, _)) = (csi, digit0, tag("I")).parse(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::CursorForwardTab(str::parse(b).unwrap_or(1)),
+        },
+    ))
+}
+
+fn parse_cursor_backward_tab(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, b, _)) = (csi, digit0, tag("Z")).parse(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::CursorBackwardTab(str::parse(b).unwrap_or(1)),
+        },
+    ))
+}
+
+fn parse_insert_character(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, b, _)) = (csi, digit0, tag("@")).parse(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::InsertCharacter(str::parse(b).unwrap_or(1)),
+        },
+    ))
+}
+
+fn parse_delete_character(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, b, _)) = (csi, digit0, tag("P")).parse(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::DeleteCharacter(str::parse(b).unwrap_or(1)),
+        },
+    ))
+}
+
+fn parse_erase_character(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, b, _)) = (csi, digit0, tag("X")).parse(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::EraseCharacter(str::parse(b).unwrap_or(1)),
+        },
+    ))
+}
+
+fn parse_insert_line(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, b, _)) = (csi, digit0, tag("L")).parse(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::InsertLine(str::parse(b).unwrap_or(1)),
+        },
+    ))
+}
+
+fn parse_delete_line(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, b, _)) = (csi, digit0, tag("M")).parse(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::DeleteLine(str::parse(b).unwrap_or(1)),
+        },
+    ))
+}
+
 fn parse_scroll_up(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b["), digit0, tag_no_case("s")).parse(input)?;
+    let (rem, (_, b, _)) = (csi, digit0, tag("S")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::ScrollUp(str::parse(b).unwrap_or(0)),
+            range: (input.len(), rem.len()),
+            sgr: Sgr::ScrollUp(str::parse(b).unwrap_or(1)),
         },
     ))
 }
 fn parse_scroll_down(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b["), digit0, tag_no_case("t")).parse(input)?;
+    let (rem, (_, b, _)) = (csi, digit0, tag("T")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::ScrollDown(str::parse(b).unwrap_or(0)),
+            range: (input.len(), rem.len()),
+            sgr: Sgr::ScrollDown(str::parse(b).unwrap_or(1)),
         },
     ))
 }
 
-fn parse_horizontal_vertical_position(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, x, _, y, _)) =
-        (tag("\x1b["), digit0, tag(":"), digit0, tag_no_case("f")).parse(input)?;
+fn parse_cursor_save(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = (csi, tag("s")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::HorizontalVerticalPosition(
-                str::parse(x).unwrap_or(0),
-                str::parse(y).unwrap_or(0),
-            ),
+            range: (input.len(), rem.len()),
+            sgr: Sgr::CursorSave,
         },
     ))
 }
 
-fn parse_aux_port_on(input: &str) -> IResult<&str, Token> {
-    let (rem, _) = tag("\x1b5i").parse(input)?;
+fn parse_cursor_restore(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = (csi, tag("u")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::AUXPortOn,
+            range: (input.len(), rem.len()),
+            sgr: Sgr::CursorRestore,
         },
     ))
 }
-fn parse_aux_port_off(input: &str) -> IResult<&str, Token> {
-    let (rem, _) = tag("\x1b[4i").parse(input)?;
+
+fn parse_dec_save_cursor(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = tag("\x1b7").parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::AUXPortOff,
+            range: (input.len(), rem.len()),
+            sgr: Sgr::DecSaveCursor,
         },
     ))
 }
-fn parse_device_status_report(input: &str) -> IResult<&str, Token> {
-    let (rem, _) = tag("\x1b[6n").parse(input)?;
+
+fn parse_dec_restore_cursor(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = tag("\x1b8").parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::DeviceStatusReport,
+            range: (input.len(), rem.len()),
+            sgr: Sgr::DecRestoreCursor,
         },
     ))
 }
 
-fn parse_cursor_hide(input: &str) -> IResult<&str, Token> {
-    let (rem, _) = (tag("\x1b["), opt(tag("?")), digit0, tag_no_case("l")).parse(input)?;
+fn parse_horizontal_vertical_position(input: &str) -> IResult<&str, Token> {
+    let (rem, ((row, col), _)) = (parse_row_col, tag("f")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::CursorHide,
+            range: (input.len(), rem.len()),
+            sgr: Sgr::HorizontalVerticalPosition(row, col),
         },
     ))
 }
-fn parse_cursor_hide_windows(input: &str) -> IResult<&str, Token> {
-    let (rem, _) = tag("\x1b[?2004h").parse(input)?;
+
+fn parse_set_scroll_region(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, top, bottom, _)) =
+        (csi, digit0, opt((tag(";"), digit0)), tag("r")).parse(input)?;
+    let bottom = bottom.map_or("", |(_, b)| b);
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::CursorHide,
+            range: (input.len(), rem.len()),
+            sgr: Sgr::SetScrollRegion(
+                str::parse(top).unwrap_or(1),
+                str::parse(bottom).unwrap_or(0),
+            ),
         },
     ))
 }
 
-fn parse_title(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, s, _)) = (tag("\x1b]0;"), take_until("\x07"), tag("\x07")).parse(input)?;
+fn parse_reverse_index(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = alt((tag("\x1bM"), tag("\u{8d}"))).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::Title(s.into()),
+            range: (input.len(), rem.len()),
+            sgr: Sgr::ReverseIndex,
         },
     ))
 }
 
-fn parse_cwd(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, s, _)) = (tag("\x1b]7;"), take_until("\x07"), tag("\x07")).parse(input)?;
+fn parse_line_position_absolute(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, b, _)) = (csi, digit0, tag("d")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::Cwd(s.into()),
+            range: (input.len(), rem.len()),
+            sgr: Sgr::LinePositionAbsolute(str::parse(b).unwrap_or(1)),
         },
     ))
 }
-fn parse_prompt(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, _)) = (
-        tag("\x1b]133;"),
-        take_while1(|c: char| !(c.is_control() || c.is_whitespace())),
-    )
-        .parse(input)?;
+
+fn parse_character_position_absolute(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, b, _)) = (csi, digit0, tag("`")).parse(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::CharacterPositionAbsolute(str::parse(b).unwrap_or(1)),
+        },
+    ))
+}
+
+fn parse_repeat(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, b, _)) = (csi, digit0, tag("b")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::Prompt,
+            range: (input.len(), rem.len()),
+            sgr: Sgr::Repeat(str::parse(b).unwrap_or(1)),
         },
     ))
 }
-fn parse_bold(input: &str) -> IResult<&str, Token> {
-    let (rem, _) = tag("\x1b(B").parse(input)?;
+
+fn parse_aux_port_on(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = (csi, tag("5i")).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::Bold,
+            range: (input.len(), rem.len()),
+            sgr: Sgr::AUXPortOn,
+        },
+    ))
+}
+fn parse_aux_port_off(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = (csi, tag("4i")).parse(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::AUXPortOff,
+        },
+    ))
+}
+fn parse_device_status_report(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = (csi, tag("6n")).parse(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::DeviceStatusReport,
         },
     ))
 }
 
-fn parse_cursor_show(input: &str) -> IResult<&str, Token> {
-    let (rem, _) = (tag("\x1b["), opt(tag("?")), digit0, tag_no_case("h")).parse(input)?;
+fn parse_title(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, n, _, s, _)) =
+        (osc, one_of("012"), tag(";"), take_till(is_st), st).parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::CursorShow,
+            range: (input.len(), rem.len()),
+            sgr: Sgr::Title(n as u8 - b'0', s.into()),
         },
     ))
 }
 
-fn parse_color_foreground(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b[38;5;"), digit0, tag_no_case("m")).parse(input)?;
+fn parse_cwd(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, _, s, _)) = (osc, tag("7;"), take_till(is_st), st).parse(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::Cwd(s.into()),
+        },
+    ))
+}
+// xterm color spec, `rgb:r/g/b` with 1 to 4 hex digits per channel or `#rgb`
+// with the same number of digits per channel
+fn parse_color_spec(s: &str) -> Option<(u8, u8, u8)> {
+    let hex = |c: &str| !c.is_empty() && c.len() <= 4 && c.bytes().all(|b| b.is_ascii_hexdigit());
+
+    if let Some(rgb) = s.strip_prefix("rgb:") {
+        let v: Vec<_> = rgb.split('/').collect();
+        let [r, g, b] = v[..] else {
+            return None;
+        };
+        let channel = |c: &str| {
+            let n = u32::from_str_radix(c, 16).ok()?;
+            let max = (1 << (4 * c.len())) - 1;
+            Some(((n * 255 + max / 2) / max) as u8)
+        };
+        if !(hex(r) && hex(g) && hex(b)) {
+            return None;
+        }
+        return Some((channel(r)?, channel(g)?, channel(b)?));
+    }
 
-    let b = str::parse(b).unwrap_or_default();
+    let rgb = s.strip_prefix('#')?;
+    let n = rgb.len() / 3;
+    if rgb.len() % 3 != 0 || !hex(&rgb[..n]) || !rgb.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    // only the high bits are significant
+    let channel = |i: usize| {
+        let c = &rgb[i * n..(i + 1) * n];
+        let v = u8::from_str_radix(&c[..n.min(2)], 16).unwrap_or(0);
+        if n == 1 { v << 4 } else { v }
+    };
+    Some((channel(0), channel(1), channel(2)))
+}
+
+// OSC 4/10/11 set palette colors, OSC 104/110/111 reset them
+fn parse_osc_color(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, ps, body, _)) = (osc, digit1, take_till(is_st), st).parse(input)?;
+    let fail = || nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Tag));
+
+    let args: Vec<&str> = match body.strip_prefix(';') {
+        Some(body) => body.split(';').collect(),
+        None if body.is_empty() => vec![],
+        None => return Err(fail()),
+    };
 
-    let c = match b {
-        0..=7 => b + 30,
-        8..=15 => b + 82,
-        _ => {
-            return Ok((
-                rem,
-                Token {
-                    range: (input.chars().count(), rem.chars().count()),
-                    sgr: Sgr::ColorForeground(AnsiColor::Color256(b)),
-                },
-            ));
+    let sgr = match ps {
+        "4" => {
+            // queries and unknown color names are dropped
+            let mut v: Vec<_> = args
+                .chunks(2)
+                .filter_map(|i| match *i {
+                    [n, spec] => Some(Sgr::SetPalette(n.parse().ok()?, parse_color_spec(spec)?)),
+                    _ => None,
+                })
+                .collect();
+            if v.len() == 1 {
+                v.remove(0)
+            } else {
+                Sgr::List(v)
+            }
+        }
+        // OSC 10 can go on with the background, e.g. `OSC 10;fg;bg`
+        "10" | "11" => {
+            let first = if ps == "10" { 0 } else { 1 };
+            let mut v: Vec<_> = args
+                .iter()
+                .enumerate()
+                .filter_map(|(k, spec)| match first + k {
+                    0 => Some(Sgr::SetDefaultForeground(parse_color_spec(spec)?)),
+                    1 => Some(Sgr::SetDefaultBackground(parse_color_spec(spec)?)),
+                    _ => None,
+                })
+                .collect();
+            if v.len() == 1 {
+                v.remove(0)
+            } else {
+                Sgr::List(v)
+            }
         }
+        "104" => Sgr::ResetPalette(args.iter().filter_map(|n| n.parse().ok()).collect()),
+        "110" => Sgr::ResetDefaultForeground,
+        "111" => Sgr::ResetDefaultBackground,
+        _ => return Err(fail()),
     };
+
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::ColorForeground(AnsiColor::from_u8(c)),
+            range: (input.len(), rem.len()),
+            sgr,
         },
     ))
 }
-fn parse_color_background(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b[48;5;"), digit0, tag_no_case("m")).parse(input)?;
-    let b = str::parse(b).unwrap_or_default();
-
-    let c = match b {
-        0..=7 => b + 40,
-        8..=15 => b + 92,
-        _ => {
-            return Ok((
-                rem,
-                Token {
-                    range: (input.chars().count(), rem.chars().count()),
-                    sgr: Sgr::ColorBackground(AnsiColor::Color256(b)),
-                },
-            ));
-        }
+
+// \x1b]133;D;0;aid=1\x07, the options after the kind are dropped except for
+// the exit code of D
+fn parse_prompt(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, _, kind, args, _)) = (
+        osc,
+        tag("133;"),
+        satisfy(|c| !(c.is_control() || c.is_whitespace())),
+        take_while(|c: char| !(c.is_control() || c.is_whitespace())),
+        opt(st),
+    )
+        .parse(input)?;
+    let mark = match kind {
+        'A' => PromptMark::PromptStart,
+        'B' => PromptMark::CommandStart,
+        'C' => PromptMark::OutputStart,
+        'D' => PromptMark::CommandEnd(
+            args.strip_prefix(';')
+                .and_then(|i| i.split(';').next()?.parse().ok()),
+        ),
+        c => PromptMark::Other(c),
     };
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::ColorBackground(AnsiColor::from_u8(c)),
+            range: (input.len(), rem.len()),
+            sgr: Sgr::Prompt(mark),
         },
     ))
 }
-
-fn parse_color_underline(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b[58;5;"), digit0, tag_no_case("m")).parse(input)?;
+fn parse_designate(input: &str) -> IResult<&str, Token> {
+    let (rem, (_, g, c)) = (tag("\x1b"), one_of("()"), anychar).parse(input)?;
+    let charset = Charset::from_final(c);
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::ColorUnderLine(AnsiColor::from_u8(str::parse(b).unwrap_or(1))),
+            range: (input.len(), rem.len()),
+            sgr: if g == '(' {
+                Sgr::DesignateG0(charset)
+            } else {
+                Sgr::DesignateG1(charset)
+            },
         },
     ))
 }
@@ -471,7 +1145,7 @@ pub fn get_sgr(n: u8) -> Sgr {
         5 => Sgr::SlowBlink,
         6 => Sgr::RapidBlink,
         7 => Sgr::Reverse,
-        8 => Sgr::CursorHide,
+        8 => Sgr::Conceal,
         9 => Sgr::Strike,
         10 => Sgr::PrimaryFont,
         11..=19 => Sgr::AlternativeFont(n - 10),
@@ -487,45 +1161,161 @@ pub fn get_sgr(n: u8) -> Sgr {
         22 => Sgr::NormalIntensity,
         27 => Sgr::UnReversed,
         29 => Sgr::UnStrike,
-        28 => Sgr::UnHide,
+        28 => Sgr::Reveal,
         23 => Sgr::UnItalic,
 
-        _ => Sgr::Unknown(n),
+        _ => Sgr::UnknownSgr(n as u16),
     }
 }
 
-fn parse_sgr1(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, b, _)) = (tag("\x1b["), digit0, tag_no_case("m")).parse(input)?;
+// ESC [ <private> <params> <intermediates> <final>
+// params are separated by ';', sub-parameters (ITU T.416) by ':'
+#[derive(Debug, Clone)]
+struct Csi<'a> {
+    private: Option<char>,
+    params: Vec<Vec<Option<u16>>>,
+    intermediates: &'a str,
+    action: char,
+}
 
-    let n: u8 = str::parse(b).unwrap_or_default();
+fn parse_param(s: &str) -> Option<u16> {
+    if s.is_empty() {
+        return None;
+    }
+    Some(s.bytes().fold(0u16, |n, b| {
+        n.saturating_mul(10).saturating_add((b - b'0') as u16)
+    }))
+}
+
+fn parse_csi(input: &str) -> IResult<&str, Csi<'_>> {
+    let (rem, (_, private, params, intermediates, action)) = (
+        csi,
+        opt(one_of("<=>?")),
+        take_while(|c: char| c.is_ascii_digit() || c == ';' || c == ':'),
+        take_while(|c: char| ('\x20'..='\x2f').contains(&c)),
+        satisfy(|c| ('\x40'..='\x7e').contains(&c)),
+    )
+        .parse(input)?;
 
     Ok((
         rem,
-        Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: get_sgr(n),
+        Csi {
+            private,
+            params: params
+                .split(';')
+                .map(|p| p.split(':').map(parse_param).collect())
+                .collect(),
+            intermediates,
+            action,
         },
     ))
 }
 
-fn parse_color_reset(input: &str) -> IResult<&str, Token> {
-    let (rem, _) = (tag("\x1b[0"), tag_no_case("m")).parse(input)?;
+// 38;5;n with n < 16 is one of the 16 base colors
+fn get_color256(n: u8) -> AnsiColor {
+    match n {
+        0..=7 => AnsiColor::from_u8(n + 30),
+        8..=15 => AnsiColor::from_u8(n + 82),
+        _ => AnsiColor::Color256(n),
+    }
+}
+
+fn param_u8(n: Option<u16>) -> u8 {
+    n.unwrap_or(0).min(255) as u8
+}
+
+// 38:5:n, 38:2:cs:r:g:b or 38:2:r:g:b
+fn get_sub_color(sub: &[Option<u16>]) -> Option<AnsiColor> {
+    match *sub {
+        [Some(5), n, ..] => Some(get_color256(param_u8(n))),
+        [Some(2), _, r, g, b, ..] | [Some(2), r, g, b] => {
+            Some(AnsiColor::Rgb(param_u8(r), param_u8(g), param_u8(b)))
+        }
+        _ => None,
+    }
+}
+
+fn get_underline_style(n: Option<u16>) -> Sgr {
+    match n.unwrap_or(1) {
+        0 => Sgr::UnUnderlined,
+        1 => Sgr::Underline,
+        n => Sgr::UnderlineStyle(n.min(255) as u8),
+    }
+}
+
+fn get_sgr_list(params: &[Vec<Option<u16>>]) -> Sgr {
+    let mut v = Vec::new();
+    let mut iter = params.iter();
+
+    while let Some(group) = iter.next() {
+        let n = group[0].unwrap_or(0);
+        let sub = &group[1..];
+        let color = match n {
+            38 | 48 | 58 if !sub.is_empty() => get_sub_color(sub),
+            38 | 48 | 58 => {
+                let mut next = || iter.next().and_then(|i| i[0]);
+                match next() {
+                    Some(5) => Some(get_color256(param_u8(next()))),
+                    Some(2) => Some(AnsiColor::Rgb(
+                        param_u8(next()),
+                        param_u8(next()),
+                        param_u8(next()),
+                    )),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        let sgr = match (n, color) {
+            (38, Some(c)) => Sgr::ColorForeground(c),
+            (48, Some(c)) => Sgr::ColorBackground(c),
+            (58, Some(c)) => Sgr::ColorUnderLine(c),
+            (4, _) if !sub.is_empty() => get_underline_style(sub[0]),
+            _ => u8::try_from(n).map_or(Sgr::UnknownSgr(n), get_sgr),
+        };
+        v.push(sgr);
+    }
+
+    if v.len() == 1 {
+        v.remove(0)
+    } else {
+        Sgr::List(v)
+    }
+}
+
+fn parse_sgr(input: &str) -> IResult<&str, Token> {
+    let (rem, csi) = parse_csi(input)?;
+    if csi.action != 'm' || csi.private.is_some() || !csi.intermediates.is_empty() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Tag,
+        )));
+    }
+
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::ColorReset,
+            range: (input.len(), rem.len()),
+            sgr: get_sgr_list(&csi.params),
         },
     ))
 }
 
 fn parse_anychar(input: &str) -> IResult<&str, Token> {
     let (rem, c) = anychar(input)?;
+    // the marks that follow a char are part of its cell
+    let cluster = graphemes(input).next().unwrap_or_default();
+    let (rem, sgr) = if cluster.len() > c.len_utf8() {
+        (&input[cluster.len()..], Sgr::Grapheme(cluster.to_string()))
+    } else {
+        (rem, Sgr::Char(c))
+    };
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::Char(c),
+            range: (input.len(), rem.len()),
+            sgr,
         },
     ))
 }
@@ -535,7 +1325,7 @@ fn parse_bell(input: &str) -> IResult<&str, Token> {
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::Bell,
         },
     ))
@@ -546,7 +1336,7 @@ fn parse_backspace(input: &str) -> IResult<&str, Token> {
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::Backspace,
         },
     ))
@@ -557,406 +1347,67 @@ fn parse_tab(input: &str) -> IResult<&str, Token> {
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::Bell,
-        },
-    ))
-}
-
-fn parse_line_feed(input: &str) -> IResult<&str, Token> {
-    let (rem, _) = nom::character::complete::char('\x0A').parse(input)?;
-    Ok((
-        rem,
-        Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::LineFeed,
-        },
-    ))
-}
-
-fn parse_form_feed(input: &str) -> IResult<&str, Token> {
-    let (rem, _) = nom::character::complete::char('\x0C').parse(input)?;
-    Ok((
-        rem,
-        Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::FormFeed,
+            range: (input.len(), rem.len()),
+            sgr: Sgr::Tab,
         },
     ))
 }
 
-fn parse_carriage_return(input: &str) -> IResult<&str, Token> {
-    let (rem, _) = nom::character::complete::char('\x0D').parse(input)?;
+fn parse_shift_out(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = nom::character::complete::char('\x0e').parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::CarriageReturn,
+            range: (input.len(), rem.len()),
+            sgr: Sgr::ShiftOut,
         },
     ))
 }
 
-fn parse_sgr2(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, front, _, background, _)) =
-        (tag("\x1b["), digit0, tag(";"), digit0, tag_no_case("m")).parse(input)?;
-
-    let a = front.parse().unwrap_or(0);
-    let b = background.parse().unwrap_or(0);
+fn parse_shift_in(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = nom::character::complete::char('\x0f').parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::List(vec![get_sgr(a), get_sgr_color(b)]),
+            range: (input.len(), rem.len()),
+            sgr: Sgr::ShiftIn,
         },
     ))
 }
 
-fn parse_sgr3(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, ctrl, _, front, _, background, _)) = (
-        tag("\x1b["),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag_no_case("m"),
-    )
-        .parse(input)?;
-
-    let a = ctrl.parse().unwrap_or(0);
-    let b = front.parse().unwrap_or(0);
-    let c = background.parse().unwrap_or(0);
-
-    if a == 38 && b == 5 {
-        return Ok((
-            rem,
-            Token {
-                range: (input.chars().count(), rem.chars().count()),
-                sgr: Sgr::ColorForeground(AnsiColor::from_u8(c)),
-            },
-        ));
-    }
-    if a == 48 && b == 5 {
-        return Ok((
-            rem,
-            Token {
-                range: (input.chars().count(), rem.chars().count()),
-                sgr: Sgr::ColorBackground(AnsiColor::from_u8(c)),
-            },
-        ));
-    }
+fn parse_line_feed(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = nom::character::complete::char('\x0A').parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::List(vec![get_sgr(a), get_sgr_color(b), get_sgr_color(c)]),
+            range: (input.len(), rem.len()),
+            sgr: Sgr::LineFeed,
         },
     ))
 }
 
-fn parse_sgr4(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, reset, _, ctrl, _, front, _, background, _)) = (
-        tag("\x1b["),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag_no_case("m"),
-    )
-        .parse(input)?;
-    let a: u8 = reset.parse().unwrap_or(0);
-    let b: u8 = ctrl.parse().unwrap_or(0);
-    let c: u8 = front.parse().unwrap_or(0);
-    let d: u8 = background.parse().unwrap_or(0);
-
-    let mut v = vec![get_sgr(a)];
-
-    match b {
-        48 => match c {
-            5 => {
-                v.push(Sgr::ColorBackground(AnsiColor::Color256(d)));
-            }
-            _ => {
-                v.push(Sgr::ColorBackground(AnsiColor::from_u8(d)));
-            }
-        },
-        38 => match c {
-            5 => {
-                v.push(Sgr::ColorForeground(AnsiColor::Color256(d)));
-            }
-            _ => {
-                v.push(Sgr::ColorForeground(AnsiColor::from_u8(d)));
-            }
-        },
-        5 | 6 => {
-            v.push(get_sgr(b));
-            v.push(Sgr::ColorForeground(AnsiColor::from_u8(c)));
-            v.push(Sgr::ColorBackground(AnsiColor::from_u8(d)));
-        }
-        _ => {
-            v.push(get_sgr(b));
-            v.push(Sgr::ColorForeground(AnsiColor::Color256(c)));
-            v.push(Sgr::ColorBackground(AnsiColor::Color256(d)));
-        }
-    };
-
+fn parse_form_feed(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = nom::character::complete::char('\x0C').parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::List(v),
+            range: (input.len(), rem.len()),
+            sgr: Sgr::FormFeed,
         },
     ))
 }
 
-fn parse_sgr5(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, ctrl, _, ty, _, r, _, g, _, b, _)) = (
-        tag("\x1b["),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag_no_case("m"),
-    )
-        .parse(input)?;
-    let ctrl = ctrl.parse().unwrap_or(0);
-    let ty = ty.parse().unwrap_or(0);
-    let r = r.parse().unwrap_or(0);
-    let g = g.parse().unwrap_or(0);
-    let b = b.parse().unwrap_or(0);
-    if ctrl == 38 && ty == 2 {
-        return Ok((
-            rem,
-            Token {
-                range: (input.chars().count(), rem.chars().count()),
-                sgr: Sgr::ColorForeground(AnsiColor::Rgb(r, g, b)),
-            },
-        ));
-    }
-
-    if ctrl == 48 && ty == 2 {
-        return Ok((
-            rem,
-            Token {
-                range: (input.chars().count(), rem.chars().count()),
-                sgr: Sgr::ColorBackground(AnsiColor::Rgb(r, g, b)),
-            },
-        ));
-    }
-    todo!()
-}
-
-fn parse_sgr6(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, ctrl, _, ty, _, r, _, g, _, b, _, n, _)) = (
-        tag("\x1b["),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag_no_case("m"),
-    )
-        .parse(input)?;
-    let ctrl = ctrl.parse().unwrap_or(0);
-    let ty = ty.parse().unwrap_or(0);
-    let r = r.parse().unwrap_or(0);
-    let g = g.parse().unwrap_or(0);
-    let b = b.parse().unwrap_or(0);
-    let n = n.parse().unwrap_or(0);
-    if ctrl == 38 && ty == 2 {
-        return Ok((
-            rem,
-            Token {
-                range: (input.chars().count(), rem.chars().count()),
-                sgr: Sgr::ColorFgBg(AnsiColor::Rgb(r, g, b), AnsiColor::from_u8(n)),
-            },
-        ));
-    }
-
-    if ctrl == 48 && ty == 2 {
-        return Ok((
-            rem,
-            Token {
-                range: (input.chars().count(), rem.chars().count()),
-                sgr: Sgr::ColorFgBg(AnsiColor::from_u8(n), AnsiColor::Rgb(r, g, b)),
-            },
-        ));
-    }
-
-    if ctrl == 48 && ty == 5 && g == 38 && b == 5 {
-        return Ok((
-            rem,
-            Token {
-                range: (input.chars().count(), rem.chars().count()),
-                sgr: Sgr::ColorFgBg(AnsiColor::Color256(n), AnsiColor::Color256(r)),
-            },
-        ));
-    }
-    if ctrl == 38 && ty == 5 && g == 48 && b == 5 {
-        return Ok((
-            rem,
-            Token {
-                range: (input.chars().count(), rem.chars().count()),
-                sgr: Sgr::ColorFgBg(AnsiColor::Color256(r), AnsiColor::Color256(n)),
-            },
-        ));
-    }
-    todo!()
-}
-
-fn parse_sgr7(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, ctrl, _, a, _, b, _, c, _, d, _, e, _, f, _)) = (
-        tag("\x1b["),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag_no_case("m"),
-    )
-        .parse(input)?;
-    let ctrl = ctrl.parse().unwrap_or(0);
-    let a = a.parse().unwrap_or(0);
-    let b = b.parse().unwrap_or(0);
-    let c = c.parse().unwrap_or(0);
-    let d = d.parse().unwrap_or(0);
-    let e = e.parse().unwrap_or(0);
-    let f = f.parse().unwrap_or(0);
-    let mut v = vec![get_sgr(ctrl)];
-
-    match a {
-        38 => match b {
-            5 => {
-                v.push(Sgr::ColorForeground(AnsiColor::Color256(c)));
-            }
-            2 => {
-                v.push(Sgr::ColorForeground(AnsiColor::Color8(Color8::from_u8(c))));
-            }
-            _ => {}
-        },
-        48 => match b {
-            5 => {
-                v.push(Sgr::ColorBackground(AnsiColor::Color256(c)));
-            }
-            2 => {
-                v.push(Sgr::ColorBackground(AnsiColor::Color8(Color8::from_u8(c))));
-            }
-            _ => {}
-        },
-        _ => {}
-    }
-
-    match d {
-        38 => match e {
-            5 => {
-                v.push(Sgr::ColorForeground(AnsiColor::Color256(f)));
-            }
-            2 => {
-                v.push(Sgr::ColorForeground(AnsiColor::Color8(Color8::from_u8(f))));
-            }
-            _ => {}
-        },
-        48 => match e {
-            5 => {
-                v.push(Sgr::ColorBackground(AnsiColor::Color256(f)));
-            }
-            2 => {
-                v.push(Sgr::ColorBackground(AnsiColor::Color8(Color8::from_u8(f))));
-            }
-            _ => {}
-        },
-        _ => {}
-    }
-
+fn parse_carriage_return(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = nom::character::complete::char('\x0D').parse(input)?;
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::List(v),
+            range: (input.len(), rem.len()),
+            sgr: Sgr::CarriageReturn,
         },
     ))
 }
 
-fn parse_sgr10(input: &str) -> IResult<&str, Token> {
-    let (rem, (_, c1, _, t1, _, r1, _, g1, _, b1, _, c2, _, t2, _, r2, _, g2, _, b2, _)) = (
-        tag("\x1b["),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag(";"),
-        digit0,
-        tag_no_case("m"),
-    )
-        .parse(input)?;
-    let mut fg = AnsiColor::Default;
-    let mut bg = AnsiColor::Default;
-    let c1: u8 = c1.parse().unwrap_or_default();
-    let t1: u8 = t1.parse().unwrap_or_default();
-    let r1: u8 = r1.parse().unwrap_or_default();
-    let g1: u8 = g1.parse().unwrap_or_default();
-    let b1: u8 = b1.parse().unwrap_or_default();
-    if c1 == 38 && t1 == 2 {
-        fg = AnsiColor::Rgb(r1, g1, b1)
-    }
-    if c1 == 48 && t1 == 2 {
-        bg = AnsiColor::Rgb(r1, g1, b1)
-    }
-
-    let c2: u8 = c2.parse().unwrap_or_default();
-    let t2: u8 = t2.parse().unwrap_or_default();
-    let r2: u8 = r2.parse().unwrap_or_default();
-    let g2: u8 = g2.parse().unwrap_or_default();
-    let b2: u8 = b2.parse().unwrap_or_default();
-    if c2 == 38 && t2 == 2 {
-        fg = AnsiColor::Rgb(r2, g2, b2)
-    }
-    if c2 == 48 && t2 == 2 {
-        bg = AnsiColor::Rgb(r2, g2, b2)
-    }
-    Ok((
-        rem,
-        Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::ColorFgBg(fg, bg),
-        },
-    ))
-}
 fn parse_unknown(input: &str) -> IResult<&str, Token> {
     let (rem, n) = alt((
         nom::character::complete::char('\x00'),
@@ -966,8 +1417,6 @@ fn parse_unknown(input: &str) -> IResult<&str, Token> {
         nom::character::complete::char('\x04'),
         nom::character::complete::char('\x05'),
         nom::character::complete::char('\x06'),
-        nom::character::complete::char('\x0e'),
-        nom::character::complete::char('\x0f'),
         nom::character::complete::char('\x10'),
         nom::character::complete::char('\x11'),
         nom::character::complete::char('\x12'),
@@ -978,24 +1427,16 @@ fn parse_unknown(input: &str) -> IResult<&str, Token> {
         nom::character::complete::char('\x1b'),
         nom::character::complete::char('\x1c'),
         nom::character::complete::char('\x1e'),
+        // C1 controls that don't start a known sequence
+        satisfy(|c| ('\u{80}'..='\u{9f}').contains(&c)),
     ))
     .parse(input)?;
 
-    if n == '\x1a' {
-        return Ok((
-            rem,
-            Token {
-                range: (input.chars().count(), rem.chars().count()),
-                sgr: Sgr::Char('␦'),
-            },
-        ));
-    }
-
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
-            sgr: Sgr::Unknown(n as u8),
+            range: (input.len(), rem.len()),
+            sgr: Sgr::Control(n),
         },
     ))
 }
@@ -1011,7 +1452,7 @@ fn parse_link_no_title(input: &str) -> IResult<&str, Token> {
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::Link(url.to_string(), url.to_string()),
         },
     ))
@@ -1030,7 +1471,7 @@ fn parse_link_with_title(input: &str) -> IResult<&str, Token> {
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::Link(url.to_string(), title.to_string()),
         },
     ))
@@ -1048,14 +1489,34 @@ fn parse_link_ll(input: &str) -> IResult<&str, Token> {
     Ok((
         rem,
         Token {
-            range: (input.chars().count(), rem.chars().count()),
+            range: (input.len(), rem.len()),
             sgr: Sgr::Link(url.to_string(), title.to_string()),
         },
     ))
 }
 
-pub(crate) fn parse_ansi(input: &str) -> IResult<&str, Vec<Token>> {
-    let mut v = many0(alt((
+// a CSI sequence none of the parsers above know, e.g. DECSCUSR or a device
+// attributes request, is consumed so it doesn't end up in the text
+fn parse_unknown_csi(input: &str) -> IResult<&str, Token> {
+    let (rem, _) = parse_csi(input)?;
+    Ok((
+        rem,
+        Token {
+            range: (input.len(), rem.len()),
+            sgr: Sgr::UnknownCsi(input[..input.len() - rem.len()].to_string()),
+        },
+    ))
+}
+
+fn parse_token(input: &str) -> IResult<&str, Token> {
+    // every sequence starts with a control char, skip trying them on plain text
+    if input.chars().next().is_some_and(|c| !c.is_control()) {
+        return parse_anychar(input);
+    }
+
+    alt((
+        // the most common sequence first
+        parse_sgr,
         alt((
             parse_bell,
             parse_backspace,
@@ -1063,11 +1524,14 @@ pub(crate) fn parse_ansi(input: &str) -> IResult<&str, Vec<Token>> {
             parse_line_feed,
             parse_form_feed,
             parse_carriage_return,
+            parse_shift_out,
+            parse_shift_in,
             parse_title,
-            parse_decrst,
-            parse_decset,
+            parse_osc_color,
+            parse_mode,
             parse_keypad,
             parse_ext,
+            parse_reverse_index,
         )),
         alt((
             parse_cursor_up,
@@ -1083,29 +1547,29 @@ pub(crate) fn parse_ansi(input: &str) -> IResult<&str, Vec<Token>> {
             parse_scroll_up,
             parse_scroll_down,
             parse_horizontal_vertical_position,
+            parse_line_position_absolute,
+            parse_character_position_absolute,
+            parse_repeat,
+            parse_set_scroll_region,
             parse_aux_port_on,
             parse_aux_port_off,
             parse_device_status_report,
         )),
         alt((
-            parse_bold,
-            parse_cursor_hide,
-            parse_cursor_hide_windows,
-            parse_cursor_show,
-            parse_color_foreground,
-            parse_color_background,
-            parse_color_underline,
-            parse_color_reset,
-            parse_sgr1,
-        )),
-        alt((
-            parse_sgr2,
-            parse_sgr3,
-            parse_sgr4,
-            parse_sgr5,
-            parse_sgr6,
-            parse_sgr7,
-            parse_sgr10,
+            parse_designate,
+            parse_cursor_save,
+            parse_cursor_restore,
+            parse_dec_save_cursor,
+            parse_dec_restore_cursor,
+            parse_tab_set,
+            parse_tab_clear,
+            parse_cursor_forward_tab,
+            parse_cursor_backward_tab,
+            parse_insert_character,
+            parse_delete_character,
+            parse_erase_character,
+            parse_insert_line,
+            parse_delete_line,
         )),
         alt((
             parse_link_with_title,
@@ -1114,16 +1578,264 @@ pub(crate) fn parse_ansi(input: &str) -> IResult<&str, Vec<Token>> {
             parse_cwd,
             parse_prompt,
         )),
+        parse_unknown_csi,
         parse_unknown,
         parse_anychar,
-    )))
-    .parse(input)?;
+    ))
+    .parse(input)
+}
+
+/// Lazily lexes a complete input, token ranges are byte offsets into it.
+///
+/// Every byte of the input ends up in exactly one token, text that isn't a
+/// known sequence is yielded as [`Sgr::Char`], [`Sgr::Control`] or
+/// [`Sgr::UnknownCsi`].
+#[derive(Debug, Clone)]
+pub struct Tokens<'a> {
+    input: &'a str,
+    rest: &'a str,
+}
+
+impl<'a> Tokens<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Tokens { input, rest: input }
+    }
+}
+
+/// Lexes `input` into [`Token`]s.
+///
+/// ```
+/// use ansi2::lex::{Sgr, tokenize};
+/// use ansi2::color::{AnsiColor, Color8};
+///
+/// let s = "ok\n\x1b[31merror\x1b[0m\n";
+/// let red = tokenize(s)
+///     .filter(|t| t.sgr == Sgr::ColorForeground(AnsiColor::Color8(Color8::Red)))
+///     .count();
+/// assert_eq!(red, 1);
+/// ```
+pub fn tokenize(input: &str) -> Tokens<'_> {
+    Tokens::new(input)
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let (rem, mut token) = parse_token(self.rest).ok()?;
+        let start = self.input.len() - self.rest.len();
+        token.range = (start, start + self.rest.len() - rem.len());
+        self.rest = rem;
+        Some(token)
+    }
+}
+
+// like `Tokens`, but the ranges are char indices of `input`, which the
+// sourcemap of the canvas uses
+pub(crate) fn char_tokens(input: &str) -> impl Iterator<Item = Token> + '_ {
+    let mut chars = 0;
+    Tokens::new(input).map(move |mut token| {
+        let (start, end) = token.range;
+        let len = input[start..end].chars().count();
+        token.range = (chars, chars + len);
+        chars += len;
+        token
+    })
+}
+
+#[cfg(test)]
+pub(crate) fn parse_ansi(input: &str) -> IResult<&str, Vec<Token>> {
+    Ok(("", Tokens::new(input).collect()))
+}
+
+/// Converts a byte range of `input` to a char range.
+///
+/// Panics if the range isn't on char boundaries of `input`.
+pub fn char_range(input: &str, range: (usize, usize)) -> (usize, usize) {
+    let start = input[..range.0].chars().count();
+    let end = start + input[range.0..range.1].chars().count();
+    (start, end)
+}
+
+// an unfinished sequence is held back at most this many bytes before it is
+// lexed as plain text
+const MAX_PENDING: usize = 1 << 16;
 
-    for i in v.1.iter_mut() {
-        i.range = (
-            input.chars().count() - i.range.0,
-            input.chars().count() - i.range.1,
-        )
+const LINK_END: [&str; 3] = ["\x1b]8;;\x1b\\", "\x1b[!p", "\x1b]8;;\x07"];
+
+// whether `s` starts with an escape sequence that the next chunk could still complete
+fn is_incomplete(s: &str) -> bool {
+    if s.len() > MAX_PENDING {
+        return false;
+    }
+    let (csi, osc) = match s.strip_prefix('\x1b') {
+        Some("" | "(" | ")") => return true,
+        Some(body) => (body.strip_prefix('['), body.strip_prefix(']')),
+        None => (s.strip_prefix('\u{9b}'), s.strip_prefix('\u{9d}')),
+    };
+
+    if let Some(csi) = csi {
+        return csi.chars().all(|c| ('\x20'..='\x3f').contains(&c));
+    }
+
+    if let Some(osc) = osc {
+        let end = [osc.find('\x07'), osc.find("\x1b\\"), osc.find('\u{9c}')]
+            .into_iter()
+            .flatten()
+            .min();
+        return match end {
+            None => true,
+            // a link is one token from its url to the closing sequence
+            Some(end) if osc.starts_with("8;") => !LINK_END.iter().any(|i| osc[end..].contains(i)),
+            Some(_) => false,
+        };
+    }
+
+    false
+}
+
+/// Decodes `bytes` like [`String::from_utf8_lossy`], except that a stray
+/// byte in `0x80..=0x9f` is taken as the 8-bit C1 control of the same value.
+pub fn decode(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len());
+    decode_into(&mut s, bytes, |_, _, _| {});
+    s
+}
+
+// appends `bytes` decoded to `s`, `invalid` gets the position in `s`, the
+// char and the length in `bytes` of each char that isn't valid utf-8 there
+fn decode_into(s: &mut String, bytes: &[u8], mut invalid: impl FnMut(usize, char, usize)) {
+    for chunk in bytes.utf8_chunks() {
+        s.push_str(chunk.valid());
+        let c = match chunk.invalid() {
+            [] => continue,
+            &[b @ 0x80..=0x9f] => char::from(b),
+            _ => char::REPLACEMENT_CHARACTER,
+        };
+        invalid(s.len(), c, chunk.invalid().len());
+        s.push(c);
+    }
+}
+
+// length of the prefix that doesn't end inside a utf-8 sequence
+fn utf8_boundary(bytes: &[u8]) -> usize {
+    let n = bytes.len();
+    for i in 1..=n.min(4) {
+        let b = bytes[n - i];
+        if b & 0xC0 == 0x80 {
+            continue;
+        }
+        let len = match b {
+            0xF0.. => 4,
+            0xE0.. => 3,
+            0xC0.. => 2,
+            _ => 1,
+        };
+        return if len > i { n - i } else { n };
+    }
+    n
+}
+
+/// Push-style lexer, input can be fed in chunks of any size and escape
+/// sequences or utf-8 characters may straddle chunk boundaries.
+///
+/// Token ranges are byte offsets of all the input fed so far, even where it
+/// isn't valid utf-8 and [`decode`] changes its length.
+#[derive(Debug, Clone, Default)]
+pub struct Lexer {
+    bytes: Vec<u8>,
+    pending: String,
+    // input byte offset and char index of the start of `pending`
+    offset: usize,
+    chars: usize,
+    // offset in the decoded text of the start of `pending`, and where in it
+    // a char has another length than the input bytes it was decoded from,
+    // a C1 control or a U+FFFD
+    decoded: usize,
+    resized: VecDeque<(usize, usize, usize)>,
+}
+
+impl Lexer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the tokens that are complete so far.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<Token> {
+        self.push(chunk);
+        self.lex(false, false)
+    }
+
+    /// Lexes everything that is still buffered.
+    pub fn finish(&mut self) -> Vec<Token> {
+        self.push_rest();
+        self.lex(true, false)
+    }
+
+    // like `feed` and `finish`, but the ranges are char indices like the
+    // ones of `char_tokens`
+    pub(crate) fn feed_chars(&mut self, chunk: &[u8]) -> Vec<Token> {
+        self.push(chunk);
+        self.lex(false, true)
+    }
+
+    pub(crate) fn finish_chars(&mut self) -> Vec<Token> {
+        self.push_rest();
+        self.lex(true, true)
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        self.bytes.extend_from_slice(chunk);
+        let n = utf8_boundary(&self.bytes);
+        let bytes: Vec<u8> = self.bytes.drain(..n).collect();
+        self.decode(&bytes);
+    }
+
+    fn push_rest(&mut self) {
+        let bytes = std::mem::take(&mut self.bytes);
+        self.decode(&bytes);
+    }
+
+    fn decode(&mut self, bytes: &[u8]) {
+        let start = self.decoded;
+        let resized = &mut self.resized;
+        decode_into(&mut self.pending, bytes, |at, c, len| {
+            resized.push_back((start + at, c.len_utf8(), len));
+        });
+    }
+
+    fn lex(&mut self, finish: bool, chars: bool) -> Vec<Token> {
+        let mut v = Vec::new();
+        let mut rest = self.pending.as_str();
+        while !rest.is_empty() && (finish || !is_incomplete(rest)) {
+            let Ok((rem, mut token)) = parse_token(rest) else {
+                break;
+            };
+            let len = token.range.0 - token.range.1;
+            let n = rest[..len].chars().count();
+            let mut bytes = len;
+            while let Some(&(at, decoded, input)) = self.resized.front()
+                && at < self.decoded + len
+            {
+                bytes = bytes - decoded + input;
+                self.resized.pop_front();
+            }
+            token.range = if chars {
+                (self.chars, self.chars + n)
+            } else {
+                (self.offset, self.offset + bytes)
+            };
+            self.offset += bytes;
+            self.chars += n;
+            self.decoded += len;
+            v.push(token);
+            rest = rem;
+        }
+        let n = self.pending.len() - rest.len();
+        self.pending.drain(..n);
+        v
     }
-    Ok(v)
 }
@@ -2,6 +2,7 @@ pub mod ans;
 pub mod canvas;
 pub mod color;
 pub mod css;
+pub mod error;
 pub mod html;
 pub mod image;
 pub mod lex;
@@ -9,11 +10,32 @@ pub mod node;
 pub mod svg;
 pub mod text;
 pub mod theme;
+pub mod width;
 
 #[cfg(test)]
 mod test {
-    use crate::{canvas::Canvas, lex::parse_ansi};
+    use crate::{
+        ans::to_ans,
+        canvas::{Canvas, CanvasStream, View},
+        color::{AnsiColor, Color8},
+        error::Error,
+        html::to_html,
+        lex::{DecMode, Lexer, PromptMark, Sgr, char_range, parse_ansi, tokenize},
+        svg::to_svg,
+        text::row_to_text,
+        theme::Theme,
+        width::{graphemes, str_width},
+    };
     use insta::assert_debug_snapshot;
+
+    // the rows of a canvas as text without trailing spaces
+    fn canvas_text(canvas: &Canvas) -> Vec<String> {
+        canvas.pixels[..canvas.h]
+            .iter()
+            .map(|row| row_to_text(row).trim_end().to_string())
+            .collect()
+    }
+
     #[test]
     fn test_plain() {
         let s = "ansi";
@@ -143,6 +165,78 @@ mod test {
         assert_debug_snapshot!(canvas.minify());
     }
 
+    #[test]
+    fn test_conceal() {
+        let s = "\x1b[44;8ma\x1b[28mb\x1b[?25lc";
+        assert_eq!(
+            tokenize(s).map(|t| t.sgr).collect::<Vec<_>>()[..4],
+            [
+                Sgr::List(vec![
+                    Sgr::ColorBackground(AnsiColor::Color8(Color8::Blue)),
+                    Sgr::Conceal
+                ]),
+                Sgr::Char('a'),
+                Sgr::Reveal,
+                Sgr::Char('b'),
+            ]
+        );
+        let canvas = Canvas::new(s, None);
+        let row = &canvas.pixels[0];
+        assert!(row[0].conceal && !row[1].conceal && !row[2].conceal);
+        assert!(to_ans(s, None).starts_with("\x1b[8;44ma\x1b[28mbc"));
+
+        // the background of concealed text is still drawn
+        let html = to_html(s, Theme::Vscode, None, None, None, None, None, None, false);
+        assert!(html.contains("<p class='t h b4'>a</p>"));
+        assert!(html.contains(".h{color:transparent!important}"));
+        let svg = to_svg(
+            s,
+            Theme::Vscode,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+        );
+        assert!(svg.contains(r#"<rect x="0" y="2" width="10" height="18" class='b4'/>"#));
+    }
+
+    #[test]
+    fn test_reverse() {
+        let s = "\x1b[7ma\x1b[31mb\x1b[27mc";
+        let canvas = Canvas::new(s, None);
+        let row = &canvas.pixels[0];
+        let red = AnsiColor::Color8(Color8::Red);
+        // the colors are kept as set and swapped when drawn
+        assert!(row[0].reverse && row[0].color == AnsiColor::Default);
+        assert_eq!(row[1].draw_colors(), (AnsiColor::Default, red));
+        assert!(!row[2].reverse && row[2].color == red);
+        assert!(to_ans(s, None).starts_with("\x1b[7ma\x1b[31mb\x1b[27mc"));
+
+        // default colors are the theme's ones for the other side
+        let html = to_html(s, Theme::Vscode, None, None, None, None, None, None, false);
+        assert!(html.contains("<p class='t rc rb'>a</p><p class='t rc b1'>b</p>"));
+        assert!(html.contains(".rc{color:#FFFFFF}.rb{background:#000000}"));
+        assert!(html.contains(".rc{color:#181818}.rb{background:#E5E5E5}"));
+        let svg = to_svg(
+            s,
+            Theme::Vscode,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+        );
+        assert!(svg.contains(r#"<rect x="0" y="2" width="10" height="18" class='rb'/>"#));
+        assert!(svg.contains(".rc{fill:#FFFFFF}.rb{fill:#000000}"));
+    }
+
     #[test]
     fn test_link_id() {
         let s = "\x1b]8;id=1;http://example.com/id\x1b\\twice\x1b]8;;\x1b\\";
@@ -262,6 +356,829 @@ mod test {
         assert_debug_snapshot!(canvas.minify());
     }
 
+    #[test]
+    fn test_sgr_many() {
+        let s = "\x1b[1;3;4;38;5;208;48;2;10;20;30mlog\x1b[0;1;2;3;4;5;7;9;31;42m!";
+        let r = parse_ansi(s).unwrap();
+        assert_debug_snapshot!(r);
+
+        let canvas = Canvas::new(s, None);
+        assert_debug_snapshot!(canvas);
+        assert_debug_snapshot!(canvas.minify());
+    }
+
+    #[test]
+    fn test_unknown_csi() {
+        let s = "a\x1b[2 q\x1b[>0c\x1b[5t\x1b[?25$p\x1b[cb";
+        let sgr: Vec<_> = tokenize(s).map(|t| t.sgr).collect();
+        assert_eq!(sgr[1], Sgr::UnknownCsi("\x1b[2 q".into()));
+        assert_eq!(sgr[5], Sgr::UnknownCsi("\x1b[c".into()));
+        assert_eq!(sgr.iter().map(|i| i.to_ans()).collect::<String>(), s);
+        let canvas = Canvas::new(s, None);
+        assert_eq!(row_to_text(&canvas.pixels[0]).trim_end(), "ab");
+
+        // lowercase finals are HPR, VPR, HPB and VPB, not CUU, CNL, ED and EL
+        for s in ["\x1b[3a", "\x1b[3e", "\x1b[j", "\x1b[2k"] {
+            let sgr: Vec<_> = tokenize(s).map(|t| t.sgr).collect();
+            assert_eq!(sgr, [Sgr::UnknownCsi(s.into())]);
+        }
+        let canvas = Canvas::new("ab\ncd\x1b[j\x1b[k", None);
+        assert_eq!(canvas_text(&canvas), ["ab", "cd"]);
+    }
+
+    #[test]
+    fn test_sgr_colon() {
+        let s = "\x1b[38:2::255:128:0ma\x1b[48:5:208mb\x1b[4:3mc\x1b[38:2:1:2:3;4:0md";
+        let r = parse_ansi(s).unwrap();
+        assert_debug_snapshot!(r);
+
+        let canvas = Canvas::new(s, None);
+        assert_debug_snapshot!(canvas);
+        assert_debug_snapshot!(canvas.minify());
+    }
+
+    #[test]
+    fn test_stream() {
+        let s = "\x1b]8;;http://example.com\x1b\\link\x1b]8;;\x1b\\ 中文\x1b]0;title\x07\x1b[38;2;1;2;3mrgb\n\x1b[1mbold\x1b[0m";
+        let (_, tokens) = parse_ansi(s).unwrap();
+        for size in 1..8 {
+            let mut lexer = Lexer::new();
+            let mut stream_tokens = Vec::new();
+            let mut stream = CanvasStream::new(None);
+            for chunk in s.as_bytes().chunks(size) {
+                stream_tokens.extend(lexer.feed(chunk));
+                stream.feed(chunk).unwrap();
+            }
+            stream_tokens.extend(lexer.finish());
+            assert_eq!(format!("{stream_tokens:?}"), format!("{tokens:?}"));
+            assert_eq!(stream.finish().unwrap(), Canvas::new(s, None));
+        }
+    }
+
+    #[test]
+    fn test_stream_take_rows() {
+        let mut stream = CanvasStream::new(None);
+        stream.feed(b"a\nb\x1b").unwrap();
+        let rows = stream.take_rows();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(row_to_text(&rows[0]).trim_end(), "a");
+
+        stream.feed(b"[31mc\x1b[Ad\n").unwrap();
+        let rows = stream.take_rows();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(row_to_text(&rows[0]).trim_end(), "bcd");
+        assert_eq!(rows[0][1].color, AnsiColor::Color8(Color8::Red));
+
+        let canvas = stream.finish().unwrap();
+        assert_eq!(canvas.h, 1);
+    }
+
+    #[test]
+    fn test_byte_range() {
+        let s = "中\x1b[31m文";
+        let (_, r) = parse_ansi(s).unwrap();
+        assert_eq!(r[1].range, (3, 8));
+        assert_eq!(r[2].range, (8, 11));
+        assert_eq!(char_range(s, r[1].range), (1, 6));
+        assert_eq!(char_range(s, r[2].range), (6, 7));
+
+        // the sourcemap of the canvas and the output is in chars
+        let canvas = Canvas::new(s, None);
+        assert_eq!(canvas.pixels[0][2].text_r, (6, 7));
+        assert_eq!(canvas.pixels[0][2].color_r, (1, 6));
+        let html = to_html(s, Theme::Vscode, None, None, None, None, None, None, true);
+        assert!(html.contains("text:6:7 color:1:6"));
+        let mut stream = CanvasStream::new(None);
+        stream.feed(&s.as_bytes()[..4]).unwrap();
+        stream.feed(&s.as_bytes()[4..]).unwrap();
+        assert_eq!(stream.finish().unwrap().pixels[0][2].text_r, (6, 7));
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let s = "ok\n\x1b[31merror\x1b[0m\n\x1b[1;31mfatal\x1b[m\n\x1b[32mok\x1b[m\n";
+        let is_red = |sgr: &Sgr| sgr == &Sgr::ColorForeground(AnsiColor::Color8(Color8::Red));
+        let mut red = false;
+        let mut lines = 0;
+        for token in tokenize(s) {
+            match token.sgr {
+                Sgr::List(ref v) if v.iter().any(is_red) => red = true,
+                ref sgr if is_red(sgr) => red = true,
+                Sgr::LineFeed => {
+                    lines += red as usize;
+                    red = false;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(lines, 2);
+
+        let tokens: Vec<_> = tokenize(s).collect();
+        assert_eq!(tokens.first().unwrap().range.0, 0);
+        assert_eq!(tokens.last().unwrap().range.1, s.len());
+        assert!(tokens.windows(2).all(|w| w[0].range.1 == w[1].range.0));
+        assert_eq!(tokenize("\x1b[").count(), 2);
+    }
+
+    #[test]
+    fn test_encode() {
+        let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let assets_dir = std::path::Path::new(&cargo_dir)
+            .parent()
+            .unwrap()
+            .join("assets");
+        for i in std::fs::read_dir(assets_dir).unwrap() {
+            let p = i.unwrap().path();
+            if p.extension().is_none_or(|ext| ext != "ans") {
+                continue;
+            }
+            let s = std::fs::read_to_string(&p).unwrap();
+            let v1: Vec<_> = tokenize(&s).map(|t| t.sgr).collect();
+            let ans: String = v1.iter().map(|i| i.to_ans()).collect();
+            let v2: Vec<_> = tokenize(&ans).map(|t| t.sgr).collect();
+            assert_eq!(v1, v2, "{}", p.display());
+        }
+
+        let s = "a\tb\x1b[2Jc\x1b[3;4Ad\x1b]0;title\x07\x1b]8;;http://a.b\x1b\\link\x1b]8;;\x1b\\\x1b[1;4:3;58;5;1m\x1b[?2004l\x1b]133;A\x07\x1b[5i";
+        let v: Vec<_> = tokenize(s).map(|t| t.sgr).collect();
+        let ans: String = v.iter().map(|i| i.to_ans()).collect();
+        assert_eq!(tokenize(&ans).map(|t| t.sgr).collect::<Vec<_>>(), v);
+
+        // unknown SGR params and controls are encoded as they were written
+        let s = "\x1b[26m\x1b[1;26m\x1b[999m\x1a\x01a\u{9c}";
+        assert_eq!(
+            tokenize(s).map(|t| t.sgr).collect::<Vec<_>>()[3..],
+            [
+                Sgr::Control('\x1a'),
+                Sgr::Control('\x01'),
+                Sgr::Char('a'),
+                Sgr::Control('\u{9c}')
+            ]
+        );
+        assert_eq!(tokenize(s).map(|t| t.sgr.to_ans()).collect::<String>(), s);
+        assert_eq!(Canvas::new("a\x1a", None).pixels[0][1].text, "␦");
+
+        let s = "\x1b[1;41;32mred\x1b[48;5;200m\x1b[0m";
+        let ans: String = tokenize(s)
+            .map(|t| match t.sgr {
+                Sgr::ColorBackground(_) => String::new(),
+                Sgr::List(v) => Sgr::List(
+                    v.into_iter()
+                        .filter(|i| !matches!(i, Sgr::ColorBackground(_)))
+                        .collect(),
+                )
+                .to_ans(),
+                sgr => sgr.to_ans(),
+            })
+            .collect();
+        assert_eq!(ans, "\x1b[1;32mred\x1b[0m");
+    }
+
+    #[test]
+    fn test_try_new() {
+        let s = "a\x1b[99999999Bb";
+        assert_eq!(
+            Canvas::try_new(s, None),
+            Err(Error::TooLarge { w: 2, h: 100000000 })
+        );
+        let mut stream = CanvasStream::new(None);
+        assert!(stream.feed(s.as_bytes()).is_err());
+
+        let s = "a\x1b[10Cb\tc\x1b[5Bd";
+        assert_eq!(Canvas::try_new(s, None), Ok(Canvas::new(s, None)));
+        assert_eq!(Canvas::try_new(s, Some(0)), Ok(Canvas::new(s, None)));
+    }
+
+    #[test]
+    fn test_osc_palette() {
+        let s = "\x1b]4;1;rgb:ff/80/0;2;#00F;3;?\x07\x1b]10;rgb:ffff/ffff/ffff\x1b\\\x1b]11;#102030\x07\x1b[31mred\x1b[32mgreen\x1b[33myellow\x1b]104;2\x07";
+        let r = parse_ansi(s).unwrap();
+        assert_debug_snapshot!(r);
+
+        let canvas = Canvas::new(s, None);
+        assert_eq!(
+            canvas.palette.colors.into_iter().collect::<Vec<_>>(),
+            [(1, (255, 128, 0))]
+        );
+        assert_eq!(canvas.palette.foreground, Some((255, 255, 255)));
+        assert_eq!(canvas.palette.background, Some((16, 32, 48)));
+
+        let html = to_html(s, Theme::Vscode, None, None, None, None, None, None, false);
+        assert!(html.contains(".c1{color:#FF8000}"));
+        assert!(html.contains("background: #102030"));
+        assert!(html.contains("div{color:#FFFFFF}"));
+        // the reset brings back the theme color
+        assert!(html.contains(".c2{color:#0DBC79}"));
+
+        let ans = to_ans(s, None);
+        assert_eq!(
+            Canvas::new(&ans, None).palette,
+            Canvas::new(s, None).palette
+        );
+
+        let s = "\x1b]4;1;rgb:ff/00/00\x07\x1b]110\x07\x1b]111\x07\x1b]104\x07";
+        assert_eq!(Canvas::new(s, None).palette, Default::default());
+    }
+
+    #[test]
+    fn test_c1() {
+        let seven = "\x1b]0;title\x1b\\\x1b]7;file:///tmp\x1b\\\x1b[31mred\x1b[2Ax\x1b]133;A\x1b\\";
+        let eight =
+            "\u{9d}0;title\u{9c}\u{9d}7;file:///tmp\u{9c}\u{9b}31mred\u{9b}2Ax\u{9d}133;A\u{9c}";
+        let sgr = |s| tokenize(s).map(|t| t.sgr).collect::<Vec<_>>();
+        assert_eq!(sgr(seven), sgr(eight));
+        assert_eq!(
+            sgr(seven)[..2],
+            [
+                Sgr::Title(0, "title".into()),
+                Sgr::Cwd("file:///tmp".into())
+            ]
+        );
+        assert_eq!(sgr("\x1b]0;title\x07"), [Sgr::Title(0, "title".into())]);
+        assert_eq!(sgr("\x1b]2;title\x07"), [Sgr::Title(2, "title".into())]);
+        assert_eq!(Sgr::Title(1, "icon".into()).to_ans(), "\x1b]1;icon\x07");
+        assert_eq!(
+            sgr("a\u{9c}b"),
+            [Sgr::Char('a'), Sgr::Control('\u{9c}'), Sgr::Char('b')]
+        );
+
+        // raw 8-bit controls, split across chunks
+        let mut lexer = Lexer::new();
+        let mut v = lexer.feed(b"\x9d0;ti");
+        v.extend(lexer.feed(b"tle\x9c\x9b3"));
+        v.extend(lexer.feed(b"1mred\x9b2Ax"));
+        v.extend(lexer.finish());
+        assert_eq!(
+            v.into_iter().map(|t| t.sgr).collect::<Vec<_>>(),
+            sgr("\x1b]0;title\x07\x1b[31mred\x1b[2Ax")
+        );
+
+        // ranges are offsets of the input bytes, not of the decoded text
+        let mut lexer = Lexer::new();
+        let mut v = lexer.feed(b"\x9b31ma\xff\xfeb\xe4\xb8");
+        v.extend(lexer.feed(b"\xadc"));
+        v.extend(lexer.finish());
+        assert_eq!(
+            v.into_iter().map(|t| t.range).collect::<Vec<_>>(),
+            [(0, 4), (4, 5), (5, 6), (6, 7), (7, 8), (8, 11), (11, 12)]
+        );
+    }
+
+    #[test]
+    fn test_charset() {
+        // G0 line drawing, then G1 line drawing invoked with SO
+        let s = "\x1b(0lqk\x1b(B\nx\x1b)0\x0ex\x0fx\n\x1b(0mqj\x1b(Bok";
+        let canvas = Canvas::new(s, None);
+        let text: Vec<String> = canvas
+            .pixels
+            .iter()
+            .map(|row| row.iter().map(|n| n.text.as_str()).collect())
+            .collect();
+        let text: Vec<_> = text.iter().map(|row| row.trim_end()).collect();
+        assert_eq!(text[..3], ["┌─┐", "x│x", "└─┘ok"]);
+        assert!(canvas.pixels.iter().flatten().all(|n| !n.bold));
+
+        let r: String = tokenize(s).map(|t| t.sgr.to_ans()).collect();
+        assert_eq!(r, s);
+    }
+
+    #[test]
+    fn test_cursor_addressing() {
+        let s = "\x1b[5;10H\x1b[H\x1b[;7f\x1b[3d\x1b[4`\x1b[2b";
+        let sgr: Vec<_> = tokenize(s).map(|t| t.sgr).collect();
+        assert_eq!(
+            sgr,
+            [
+                Sgr::CursorPosition(5, 10),
+                Sgr::CursorPosition(1, 1),
+                Sgr::HorizontalVerticalPosition(1, 7),
+                Sgr::LinePositionAbsolute(3),
+                Sgr::CharacterPositionAbsolute(4),
+                Sgr::Repeat(2),
+            ]
+        );
+        let r: String = sgr.iter().map(|i| i.to_ans()).collect();
+        assert_eq!(tokenize(&r).map(|t| t.sgr).collect::<Vec<_>>(), sgr);
+
+        // corners of a 10x5 box, then one char with each addressing sequence
+        let s = concat!(
+            "\x1b[1;1H+\x1b[1;10H+\x1b[5;1H+\x1b[5;10H+",
+            "\x1b[1;3H-\x1b[5b",
+            "\x1b[2d\x1b[3`Y",
+            "\x1b[3;5fX",
+            "\x1b[4;1H\x1b[99GZ",
+            "\x1b[H#",
+        );
+        let canvas = Canvas::new(s, Some(10));
+        let text: Vec<String> = canvas
+            .pixels
+            .iter()
+            .map(|row| row.iter().map(|n| n.text.as_str()).collect())
+            .collect();
+        let text: Vec<_> = text.iter().map(|row| row.trim_end()).collect();
+        assert_eq!(
+            text[..5],
+            ["# ------ +", "  Y", "    X", "         Z", "+        +"]
+        );
+
+        // a huge REP count is cut to one that leaves the same screen
+        let canvas = Canvas::try_new_screen("a\x1b[2147483647b", Some(4), 2).unwrap();
+        assert_eq!(canvas_text(&canvas.view(View::Screen)), ["aaaa", "aaaa"]);
+        let canvas = Canvas::try_new_screen("中\x1b[2147483646b", Some(5), 2).unwrap();
+        assert_eq!(canvas_text(&canvas.view(View::Screen)), ["中中", "中"]);
+    }
+
+    #[test]
+    fn test_scroll_region() {
+        let text = |s: &str| canvas_text(&Canvas::new(s, None));
+        let s = "1\n2\n3\n4\n5\x1b[2;4r";
+        assert_eq!(tokenize(s).last().unwrap().sgr, Sgr::SetScrollRegion(2, 4));
+
+        // a line feed at the bottom margin scrolls the region only
+        assert_eq!(
+            text(&format!("{s}\x1b[4;1H\nx"))[..5],
+            ["1", "3", "4", "x", "5"]
+        );
+        assert_eq!(text(&format!("{s}\x1b[S"))[..5], ["1", "3", "4", "", "5"]);
+        assert_eq!(text(&format!("{s}\x1b[2T"))[..5], ["1", "", "", "2", "5"]);
+        // reverse index at the top margin scrolls it down
+        assert_eq!(
+            text(&format!("{s}\x1b[2;1H\x1bMx"))[..5],
+            ["1", "x", "2", "3", "5"]
+        );
+        // without a region the canvas grows, nothing is pushed off its top
+        assert_eq!(text("1\n2\n3\x1b[Sx"), ["1", "2", "3", " x"]);
+        assert_eq!(text("1\n2\x1b[H\x1b[2Sx"), ["1", "2", "x", ""]);
+        // or its bottom when scrolled down
+        assert_eq!(text("a\nb\x1b[H\x1bMc")[..3], ["c", "a", "b"]);
+        assert_eq!(text("a\nb\x1b[2T")[..4], ["", "", "a", "b"]);
+        // the cursor is homed and a reset region ends at the last row again
+        assert_eq!(
+            text(&format!("{s}x\x1b[r\x1b[5;1H\nx"))[..6],
+            ["x", "2", "3", "4", "5", "x"]
+        );
+
+        let mut stream = CanvasStream::new(None);
+        stream.feed(b"1\n2\n3\x1b[2;3r\x1b[3;1H\n4").unwrap();
+        assert_eq!(stream.take_rows().len(), 1);
+        assert_eq!(
+            stream.canvas().pixels[..2]
+                .iter()
+                .map(|row| row[0].text.as_str())
+                .collect::<Vec<_>>(),
+            ["3", "4"]
+        );
+    }
+
+    #[test]
+    fn test_screen() {
+        let text = |canvas: Canvas| canvas_text(&canvas);
+
+        let s = "1\n2\n3\n4\n5";
+        let canvas = Canvas::new_screen(s, Some(4), 3);
+        assert_eq!(canvas.scrollback, 2);
+        assert_eq!((canvas.w, canvas.h), (4, 5));
+        assert_eq!(text(canvas.view(View::Screen)), ["3", "4", "5"]);
+        assert_eq!(text(canvas.view(View::All)), ["1", "2", "3", "4", "5"]);
+        assert_eq!(text(canvas.view(View::Last(4))), ["2", "3", "4", "5"]);
+        assert_eq!(canvas.view(View::Screen).view(View::Screen).h, 3);
+
+        // the cursor is addressed and clamped within the screen
+        let canvas = Canvas::new_screen(format!("{s}\x1b[1;1Hx\x1b[99Ay\x1b[99Bz"), None, 3);
+        assert_eq!(text(canvas.view(View::Screen)), ["xy", "4", "5 z"]);
+        // a short screen is drawn whole
+        assert_eq!(
+            text(Canvas::new_screen("a", None, 3).view(View::All)),
+            ["a", "", ""]
+        );
+        // rows scrolled out of a region don't go to the scrollback
+        let canvas = Canvas::new_screen(format!("{s}\x1b[1;2r\x1b[2;1H\nx"), None, 3);
+        assert_eq!(canvas.scrollback, 2);
+        assert_eq!(text(canvas.view(View::Screen)), ["4", "x", "5"]);
+        // as with SU on the whole screen
+        let canvas = Canvas::new_screen(format!("{s}\x1b[2S"), None, 3);
+        assert_eq!(
+            text(canvas.view(View::All)),
+            ["1", "2", "3", "4", "5", "", ""]
+        );
+
+        assert_eq!(
+            Canvas::try_new_screen(s, None, 1 << 25),
+            Err(Error::TooLarge { w: 2, h: 1 << 25 })
+        );
+    }
+
+    #[test]
+    fn test_alternate_screen() {
+        let text = |canvas: &Canvas| canvas_text(&canvas.view(View::Screen));
+
+        // 1049 saves the cursor, the normal screen isn't drawn on
+        let s = "$ vim\n\x1b[?1049h\x1b[2J\x1b[Hfile\n~\x1b[?1049l$ ";
+        let canvas = Canvas::new(s, None);
+        assert_eq!(text(&canvas), ["$ vim", "$"]);
+        let alternate = canvas.alternate.as_deref().unwrap();
+        assert_eq!(text(alternate), ["file", "~"]);
+
+        // 1049 clears the alternate screen when it is entered again, 47 doesn't
+        let canvas = Canvas::new(format!("{s}\x1b[?1049h\x1b[Hx\x1b[?1049l"), None);
+        assert_eq!(text(canvas.alternate.as_deref().unwrap()), ["x", ""]);
+        let canvas = Canvas::new("\x1b[?47hab\x1b[?47l\x1b[?47h\rx\x1b[?47l", None);
+        assert_eq!(text(canvas.alternate.as_deref().unwrap()), ["xb"]);
+
+        // the input can end on the alternate screen, which has no scrollback
+        let canvas = Canvas::new_screen("a\nb\x1b[?1049h1\n2\n3\n4", None, 2);
+        assert_eq!(text(&canvas), ["a", "b"]);
+        let alternate = canvas.alternate.as_deref().unwrap();
+        assert_eq!(alternate.scrollback, 0);
+        assert_eq!(text(alternate), ["3", "4"]);
+        assert_eq!(Canvas::new("a", None).alternate, None);
+
+        // the stream holds the rows drawn on the alternate screen
+        let mut stream = CanvasStream::new(None);
+        stream.feed(b"a\n\x1b[?1049h1\n2\n").unwrap();
+        assert_eq!(stream.take_rows().len(), 0);
+        stream.feed(b"\x1b[?1049l\n").unwrap();
+        assert_eq!(stream.take_rows().len(), 2);
+    }
+
+    #[test]
+    fn test_edit() {
+        let text = |s: &str| canvas_text(&Canvas::new(s, None));
+
+        // ICH, DCH and ECH stay within the line, without a width ICH makes
+        // it longer
+        assert_eq!(text("abcde\x1b[2G\x1b[2@xy"), ["axybcde"]);
+        // cells are only pushed off a limited width
+        let canvas = Canvas::new("abcde\x1b[2G\x1b[2@", Some(6));
+        assert_eq!(canvas_text(&canvas), ["a  bcd"]);
+        assert_eq!(text("abcde\x1b[2G\x1b[2P"), ["ade"]);
+        assert_eq!(text("abcde\x1b[2G\x1b[9P"), ["a"]);
+        assert_eq!(text("abcde\x1b[2G\x1b[X"), ["a cde"]);
+        assert_eq!(text("abcde\x1b[2G\x1b[9X"), ["a"]);
+        // EL 0, 1 and 2 include the cursor column
+        assert_eq!(text("abcde\x1b[3G\x1b[K"), ["ab"]);
+        assert_eq!(text("abcde\x1b[3G\x1b[1K"), ["   de"]);
+        assert_eq!(text("abcde\x1b[3G\x1b[2K"), [""]);
+        // ED 0 and 1 erase to and from the cursor
+        assert_eq!(text("ab\ncd\nef\x1b[2;2H\x1b[J"), ["ab", "c", ""]);
+        assert_eq!(text("ab\ncd\nef\x1b[2;1H\x1b[1J"), ["", " d", "ef"]);
+        // IL and DL move the lines below the cursor and home it
+        assert_eq!(text("a\nb\nc\x1b[2;2H\x1b[Lx"), ["a", "x", "b", "c"]);
+        assert_eq!(text("a\nb\nc\x1b[1;2H\x1b[2Mx"), ["x", "", ""]);
+        // within the scroll region
+        assert_eq!(
+            text("a\nb\nc\nd\x1b[1;3r\x1b[2;1H\x1b[L"),
+            ["a", "", "b", "d"]
+        );
+
+        // ED 2 keeps the scrollback, ED 3 drops it
+        let canvas = Canvas::new_screen("1\n2\n3\n4\x1b[2J", None, 2);
+        assert_eq!(canvas.scrollback, 2);
+        let canvas = Canvas::new_screen("1\n2\n3\n4\x1b[3J", None, 2);
+        assert_eq!(canvas.scrollback, 0);
+        assert_eq!(canvas.pixels[0][0].text, "3");
+    }
+
+    #[test]
+    fn test_wrap() {
+        let text = |s: &str| canvas_text(&Canvas::new(s, Some(4)));
+
+        // a full row followed by a newline doesn't leave a blank row
+        assert_eq!(text("abcd\nefgh\r\n"), ["abcd", "efgh", ""]);
+        assert_eq!(text("abcdef"), ["abcd", "ef"]);
+        // the wrap is cancelled by cursor movements
+        assert_eq!(text("abcd\rx"), ["xbcd"]);
+        assert_eq!(text("abcd\x08x"), ["abxd"]);
+        assert_eq!(text("abcd\x1b[2Gx"), ["axcd"]);
+        // CUF and tabs stop at the last column
+        assert_eq!(text("a\x1b[9Cx"), ["a  x"]);
+        assert_eq!(text("a\t\tx"), ["a  x"]);
+        let canvas = Canvas::new("a\t\tx", Some(10));
+        assert_eq!(canvas.pixels[0][9].text, "x");
+    }
+
+    #[test]
+    fn test_tabs() {
+        let r = parse_ansi("\x1bH\u{88}\x1b[g\x1b[3g\x1b[2I\x1b[Z").unwrap();
+        assert_eq!(
+            r.1.iter().map(|t| t.sgr.clone()).collect::<Vec<_>>(),
+            [
+                Sgr::TabSet,
+                Sgr::TabSet,
+                Sgr::TabClear(0),
+                Sgr::TabClear(3),
+                Sgr::CursorForwardTab(2),
+                Sgr::CursorBackwardTab(1),
+            ]
+        );
+
+        let text = |s: &str, tab_width: usize| -> String {
+            canvas_text(&Canvas::new_with_tabs(s, Some(20), 0, tab_width)).remove(0)
+        };
+        assert_eq!(text("a\tb\tc", 8), "a       b       c");
+        assert_eq!(text("a\tb\tc", 4), "a   b   c");
+        // CHT and CBT move several stops
+        assert_eq!(text("a\x1b[2Ib", 4), "a       b");
+        assert_eq!(text("a\x1b[3I\x1b[2Zb", 4), "a   b");
+        // stops set with HTS, cleared with TBC 0 and 3
+        assert_eq!(text("\x1b[3G\x1bH\ra\tb\tc", 8), "a b     c");
+        assert_eq!(text("\x1b[9G\x1b[g\ra\tb", 8), "a               b");
+        assert_eq!(
+            text("\x1b[3g\x1b[6G\x1bH\ra\tb\tc", 8),
+            "a    b             c"
+        );
+        // no stop ahead goes to the last column, or nowhere without a width
+        assert_eq!(text("a\tb", 0), "a                  b");
+        assert_eq!(Canvas::new_with_tabs("a\tb", None, 0, 0).w, 3);
+        // the default stops
+        assert_eq!(Canvas::new("\t", None).w, 9);
+    }
+
+    #[test]
+    fn test_cursor_save() {
+        let r = parse_ansi("\x1b7\x1b8\x1b[s\x1b[u").unwrap();
+        assert_eq!(
+            r.1.iter().map(|t| t.sgr.clone()).collect::<Vec<_>>(),
+            [
+                Sgr::DecSaveCursor,
+                Sgr::DecRestoreCursor,
+                Sgr::CursorSave,
+                Sgr::CursorRestore,
+            ]
+        );
+
+        // a right prompt drawn after the cursor is saved
+        for (save, restore) in [("\x1b7", "\x1b8"), ("\x1b[s", "\x1b[u")] {
+            let s = format!("\x1b[1m~{save}\x1b[8G\x1b[31;22mright{restore}> ls");
+            let canvas = Canvas::new(s, None);
+            let row = &canvas.pixels[0];
+            assert_eq!(
+                row.iter()
+                    .map(|n| n.text.as_str())
+                    .collect::<String>()
+                    .trim_end(),
+                "~> ls  right"
+            );
+            // the attributes are restored too
+            assert!(row[1].bold && row[1].color == AnsiColor::Default);
+            assert!(!row[7].bold && row[7].color == AnsiColor::Color8(Color8::Red));
+        }
+        // the home position without a saved cursor
+        assert_eq!(Canvas::new("ab\x1b8c", None).pixels[0][0].text, "c");
+
+        let mut stream = CanvasStream::new(None);
+        stream.feed(b"a\x1b7\nb\nc\n").unwrap();
+        assert_eq!(stream.take_rows().len(), 3);
+        stream.feed(b"\x1b8x").unwrap();
+        assert_eq!(stream.canvas().pixels[0][1].text, "x");
+    }
+
+    #[test]
+    fn test_bce() {
+        let bg = |s: &str, width: Option<usize>| -> Vec<String> {
+            let canvas = Canvas::new(s, width);
+            canvas.pixels[..canvas.h]
+                .iter()
+                .map(|row| {
+                    row[..canvas.w]
+                        .iter()
+                        .map(|n| match n.bg_color {
+                            AnsiColor::Default => '.',
+                            _ => '#',
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+        let blue = AnsiColor::Color8(Color8::Blue);
+
+        // a bar painted by EL reaches a limited width
+        assert_eq!(bg("\x1b[44m ab\x1b[K\x1b[m", Some(6)), ["######"]);
+        assert_eq!(bg("abcdef\r\x1b[44m\x1b[K\x1b[m", None), ["#######"]);
+        let canvas = Canvas::new("\x1b[44m\x1b[K", Some(4));
+        assert_eq!(canvas.pixels[0][3].bg_color, blue);
+        assert_eq!(canvas.pixels[0][3].text, " ");
+        // the other erase and edit operations keep the background too
+        assert_eq!(bg("abcd\r\x1b[44m\x1b[2X\x1b[m", None), ["##..."]);
+        assert_eq!(bg("abcd\r\x1b[44m\x1b[2@\x1b[m", None), ["##...."]);
+        assert_eq!(bg("abcd\r\x1b[44m\x1b[2P\x1b[m", None), ["...##"]);
+        assert_eq!(
+            bg("a\nb\x1b[1;1H\x1b[44m\x1b[L\x1b[m", None),
+            ["##", "..", ".."]
+        );
+        assert_eq!(
+            bg("a\nbc\x08\x08\x1b[44m\x1b[1J\x1b[m", None),
+            ["###", "#.."]
+        );
+        // as do the rows a screen scrolls in
+        let canvas = Canvas::new_screen("a\n\x1b[44m\n\x1b[m", Some(2), 2);
+        assert_eq!(
+            canvas.view(View::Screen).pixels[1]
+                .iter()
+                .map(|n| n.bg_color)
+                .collect::<Vec<_>>(),
+            [blue, blue]
+        );
+        // erasing with the default background is unchanged
+        assert_eq!(bg("\x1b[44mab\x1b[m\r\x1b[K", Some(6)), ["...",]);
+    }
+
+    #[test]
+    fn test_wide() {
+        // continuation cells are shown as '_'
+        let rows = |s: &str, width: Option<usize>| -> Vec<String> {
+            let canvas = Canvas::new(s, width);
+            canvas.pixels[..canvas.h]
+                .iter()
+                .map(|row| {
+                    let row: String = row[..canvas.w]
+                        .iter()
+                        .map(|n| if n.is_continuation() { "_" } else { &n.text })
+                        .collect();
+                    row.trim_end().to_string()
+                })
+                .collect()
+        };
+
+        assert_eq!(str_width("中文ab"), 6);
+        assert_eq!(str_width("🚀"), 2);
+        assert_eq!(rows("中文ab", None), ["中_文_ab"]);
+        assert_eq!(rows("中\x1b[Cx", None), ["中_ x"]);
+        // zero width chars join the cell before them or are dropped
+        assert_eq!(str_width("a\u{200b}b"), 2);
+        assert_eq!(
+            Canvas::new("a\u{200b}b", None).pixels[0][0].text,
+            "a\u{200b}"
+        );
+        assert_eq!(rows("\u{200b}a\u{feff}", None), ["a\u{feff}"]);
+        // a wide char that does not fit goes to the next row
+        assert_eq!(rows("abc中d", Some(4)), ["abc", "中_d"]);
+        assert_eq!(rows("ab中d", Some(4)), ["ab中_", "d"]);
+        assert_eq!(rows("\x1b[?7labc中", Some(4)), ["ab中_"]);
+        // writing over either half blanks the other one
+        assert_eq!(rows("中文\x1b[2Gx", None), [" x文_"]);
+        assert_eq!(rows("中文\x1b[3Gx", None), ["中_x"]);
+        assert_eq!(rows("中文\x1b[2G\x1b[X", None), ["  文_"]);
+        // as do ICH and DCH when they cut a wide char or push it off the row
+        assert_eq!(rows("a中b\x1b[2G\x1b[P", None), ["a b"]);
+        assert_eq!(rows("a中b\x1b[3G\x1b[P", None), ["a b"]);
+        assert_eq!(rows("a中b\x1b[3G\x1b[@", None), ["a   b"]);
+        assert_eq!(rows("a中b\x1b[2G\x1b[@", None), ["a 中_b"]);
+        assert_eq!(rows("ab中\x1b[G\x1b[@", Some(4)), [" ab"]);
+
+        let s = "a中b";
+        let html = to_html(s, Theme::Vscode, None, None, None, None, None, None, false);
+        assert!(html.contains("<p class='t'>a</p><p class='t w'>中</p><p class='t'>b"));
+        assert!(html.contains(".w{width:2ch;text-align:center}"));
+        let svg = to_svg(
+            s,
+            Theme::Vscode,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+        );
+        assert!(svg.contains(r#"<text x="10" y="10" width="20""#));
+    }
+
+    #[test]
+    fn test_grapheme() {
+        let cells = |canvas: &Canvas| -> Vec<String> {
+            canvas.pixels[0][..canvas.w]
+                .iter()
+                .map(|n| n.text.clone())
+                .collect()
+        };
+        let family = "👨\u{200d}👩\u{200d}👧";
+        let s = format!("e\u{301}{family}🇨🇳\u{2764}\u{fe0f}a");
+        assert_eq!(
+            tokenize(&s).map(|t| t.sgr).collect::<Vec<_>>(),
+            [
+                Sgr::Grapheme("e\u{301}".into()),
+                Sgr::Grapheme(family.into()),
+                Sgr::Grapheme("🇨🇳".into()),
+                Sgr::Grapheme("\u{2764}\u{fe0f}".into()),
+                Sgr::Char('a'),
+            ]
+        );
+        assert_eq!(
+            cells(&Canvas::new(&s, None)),
+            [
+                "e\u{301}",
+                family,
+                "",
+                "🇨🇳",
+                "",
+                "\u{2764}\u{fe0f}",
+                "",
+                "a",
+                " "
+            ]
+        );
+        assert_eq!(str_width(&s), 8);
+        // three regional indicators are a flag and a single one
+        assert_eq!(cells(&Canvas::new("🇨🇳🇨", None)), ["🇨🇳", "", "🇨", " "]);
+        // spacing and combining marks of other scripts join their letter too
+        let s = "\u{995}\u{9bf}\u{ba8}\u{bbf}\u{c15}\u{c3f}\u{1000}\u{103c}\u{1780}\u{17b6}";
+        assert_eq!(graphemes(s).count(), 5);
+        assert_eq!(str_width(s), 5);
+        // a decomposed Hangul syllable is one wide cell, but two syllables
+        // and a vowel after a letter aren't joined
+        let s = "\u{1100}\u{1161}\u{11a8}";
+        assert_eq!(graphemes(s).collect::<Vec<_>>(), [s]);
+        assert_eq!(str_width(s), 2);
+        assert_eq!(
+            graphemes("\u{ac00}\u{11a8}\u{1100}\u{1100}\u{1161}").count(),
+            2
+        );
+        assert_eq!(graphemes("\u{ac01}\u{1161}a\u{1161}").count(), 4);
+
+        // a cluster split between chunks still takes one cell
+        let mut stream = CanvasStream::new(None);
+        stream.feed("e".as_bytes()).unwrap();
+        stream.feed("\u{301}\u{2764}".as_bytes()).unwrap();
+        stream.feed("\u{fe0f}a".as_bytes()).unwrap();
+        assert_eq!(
+            cells(stream.canvas()),
+            ["e\u{301}", "\u{2764}\u{fe0f}", "", "a", " "]
+        );
+        // but not after another token
+        assert_eq!(
+            cells(&Canvas::new("e\x1b[1m\u{301}", None)),
+            ["e", "\u{301}", " "]
+        );
+    }
+
+    #[test]
+    fn test_dec_modes() {
+        let r = parse_ansi("\x1b[?1049h\x1b[?25;2004l\x1b[?h\x1b[4h").unwrap();
+        assert_eq!(
+            r.1.iter().map(|t| t.sgr.clone()).collect::<Vec<_>>(),
+            [
+                Sgr::Decset(vec![DecMode::AlternateScreenSaveCursor]),
+                Sgr::Decrst(vec![DecMode::CursorVisible, DecMode::BracketedPaste]),
+                Sgr::Decset(vec![]),
+                Sgr::SetMode(vec![4]),
+            ]
+        );
+        for t in r.1 {
+            assert_eq!(parse_ansi(&t.sgr.to_ans()).unwrap().1[0].sgr, t.sgr);
+        }
+
+        let text = |s: &str| canvas_text(&Canvas::new(s, Some(4)));
+        // hiding the cursor doesn't hide the text
+        assert_eq!(text("\x1b[?25la\x1b[?25h")[0], "a");
+        // without autowrap the last column is overwritten
+        assert_eq!(text("\x1b[?7labcdef\x1b[?7hgh")[..2], ["abcg", "h"]);
+        // in origin mode rows are addressed from the top margin
+        assert_eq!(
+            text("\n\n\n\n\x1b[2;3r\x1b[?6h\x1b[1;2Ha\x1b[9;1Hb\x1b[?6l\x1b[1;1Hc")[..4],
+            ["c", " a", "b", ""]
+        );
+    }
+
+    #[test]
+    fn test_prompt_zones() {
+        let s = "\x1b]133;A\x07$ \x1b]133;B\x07ls\n\x1b]133;C\x07a\nb\n\x1b]133;D;1\x07\x1b]133;A\x07$ ";
+        let r = parse_ansi(s).unwrap();
+        assert_eq!(
+            r.1.iter()
+                .filter_map(|t| match &t.sgr {
+                    Sgr::Prompt(mark) => Some(*mark),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            [
+                PromptMark::PromptStart,
+                PromptMark::CommandStart,
+                PromptMark::OutputStart,
+                PromptMark::CommandEnd(Some(1)),
+                PromptMark::PromptStart,
+            ]
+        );
+
+        let canvas = Canvas::new(s, None);
+        assert_debug_snapshot!(canvas.zones());
+
+        let html = to_html(s, Theme::Vscode, None, None, None, None, None, None, false);
+        assert!(html.contains("data-command='0' data-exit='1'"));
+        assert!(html.contains("data-command='1'>"));
+
+        let mut stream = CanvasStream::new(None);
+        stream
+            .feed(b"\x1b]133;A\x07$ \x1b]133;B\x07ls\n\x1b]133;C\x07a\n")
+            .unwrap();
+        assert!(stream.take_rows().is_empty());
+        stream.feed(b"b\n\x1b]133;D;1\x07").unwrap();
+        let taken = stream.take();
+        assert_eq!(taken.pixels.len(), 3);
+        assert_eq!(taken.zones(), canvas.zones()[..3]);
+    }
+
     #[test]
     fn test_starship() {
         let s =
@@ -1,13 +1,21 @@
-use ansi2::ans::to_ans;
+use ansi2::ans::canvas_to_ans;
+use ansi2::canvas::{Canvas, CanvasStream, TAB_WIDTH, View};
+use ansi2::html::HtmlStream;
 use ansi2::image::image_to_ans;
+use ansi2::lex::decode;
+use ansi2::text::TextStream;
 use ansi2::{css::Mode, theme::Theme};
-use ansi2::{html::to_html, svg::to_svg, text::to_text};
+use ansi2::{html::canvas_to_html, svg::canvas_to_svg, text::canvas_to_text};
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::Command;
+use std::process::{Command, ExitCode};
 use std::{fs::read, io::Read};
 use which_shell::{Shell, which_shell};
 
@@ -27,6 +35,26 @@ struct CommonOptions {
     #[arg(short, long)]
     width: Option<usize>,
 
+    /// Columns between tab stops, 0 leaves only the stops set by the input
+    #[arg(long, default_value_t = TAB_WIDTH)]
+    tab_width: usize,
+
+    /// Rows of the terminal screen, only the final screen is rendered
+    #[arg(long)]
+    height: Option<usize>,
+
+    /// Render the last N lines of the scrollback and screen instead, 0 renders all of them
+    #[arg(long, requires = "height")]
+    lines: Option<usize>,
+
+    /// Render the last frame of the alternate screen (vim, less, ...) instead of the normal screen
+    #[arg(long, default_value_t = false)]
+    alternate: bool,
+
+    /// Write text and html rows as soon as the cursor leaves them, rows it moves back into afterwards are not redrawn
+    #[arg(long, default_value_t = false, conflicts_with_all = ["height", "alternate"])]
+    stream: bool,
+
     #[arg(short, long)]
     theme: Option<Theme>,
 
@@ -98,6 +126,56 @@ enum Commands {
     },
 }
 
+/// Errors that end the cli, each kind exits with its own code
+#[derive(Debug)]
+enum CliError {
+    Command(String, String),
+    UnknownShell,
+    Input(std::io::Error),
+    Output(std::io::Error),
+    Font(std::io::Error),
+    Render(ansi2::error::Error),
+    #[cfg(feature = "minify")]
+    Minify(String),
+    Open(opener::OpenError),
+}
+
+impl CliError {
+    fn exit_code(&self) -> u8 {
+        // 2 is used by clap for invalid arguments
+        match self {
+            CliError::Command(..) => 1,
+            CliError::UnknownShell => 9,
+            CliError::Input(_) => 3,
+            CliError::Output(_) => 4,
+            CliError::Font(_) => 5,
+            CliError::Render(_) => 6,
+            #[cfg(feature = "minify")]
+            CliError::Minify(_) => 7,
+            CliError::Open(_) => 8,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Command(cmd, e) => write!(f, "failed to execute command '{cmd}': {e}"),
+            CliError::UnknownShell => write!(
+                f,
+                "unknown shell detected, please specify a shell using --shell option"
+            ),
+            CliError::Input(e) => write!(f, "can't read input: {e}"),
+            CliError::Output(e) => write!(f, "can't write output file: {e}"),
+            CliError::Font(e) => write!(f, "can't read font file: {e}"),
+            CliError::Render(e) => write!(f, "can't render input: {e}"),
+            #[cfg(feature = "minify")]
+            CliError::Minify(e) => write!(f, "can't compress svg: {e}"),
+            CliError::Open(e) => write!(f, "failed to open file in browser: {e}"),
+        }
+    }
+}
+
 fn process_input(buf: Vec<u8>) -> String {
     if let Some(ty) = infer::get(&buf)
         && ty.matcher_type() == infer::MatcherType::Image
@@ -106,7 +184,7 @@ fn process_input(buf: Vec<u8>) -> String {
         return s;
     }
 
-    String::from_utf8_lossy(&buf).to_string()
+    decode(&buf)
 }
 
 /// Execute a shell command and return the stdout as a string
@@ -116,7 +194,7 @@ fn exec(shell: &str, args: &[&str]) -> Result<String, String> {
         .output()
         .map_err(|e| format!("Failed to execute command: {}", e))?;
 
-    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    Ok(decode(&output.stdout))
 }
 
 /// Trim trailing "\n\x1b[m" pattern and replace with just "\x1b[m"
@@ -163,7 +241,12 @@ fn execute_command(shell: &str, command: &str) -> Result<String, String> {
     exec(shell, &["-c", command])
 }
 
-fn handle_cmd_subcommand(commands: Vec<String>, prompt: bool, shell: Shell, common: CommonOptions) {
+fn handle_cmd_subcommand(
+    commands: Vec<String>,
+    prompt: bool,
+    shell: Shell,
+    common: CommonOptions,
+) -> Result<(), CliError> {
     let format = common.format.unwrap_or(Format::Svg);
     let theme = common.theme.unwrap_or(Theme::Vscode);
 
@@ -185,69 +268,32 @@ fn handle_cmd_subcommand(commands: Vec<String>, prompt: bool, shell: Shell, comm
         }
 
         // Execute command and add output
-        match execute_command(&shell.to_string(), cmd) {
-            Ok(output) => ansi_output.push_str(&output),
-            Err(e) => {
-                eprintln!("Error executing command '{}': {}", cmd, e);
-                std::process::exit(1);
-            }
-        }
+        let output = execute_command(&shell.to_string(), cmd)
+            .map_err(|e| CliError::Command(cmd.clone(), e))?;
+        ansi_output.push_str(&output);
     }
 
     // Convert to base64 font if needed
-    let base64 = common.font.map(|font_url| {
-        if font_url.starts_with("http") {
-            return font_url;
-        }
-
-        if !Path::new(&font_url).exists() {
-            return font_url;
-        }
-
-        let bin = read(font_url).expect("read font file error");
-        let base64 = BASE64_STANDARD.encode(bin);
-        format!("data:font;base64,{base64}")
-    });
+    let base64 = common.font.clone().map(font_to_base64).transpose()?;
 
     // Format output
-    let output = match format {
-        Format::Svg => {
-            let svg = to_svg(
-                ansi_output,
-                theme,
-                common.width,
-                base64,
-                common.mode,
-                common.light_bg,
-                common.dark_bg,
-                common.font_size,
-                common.length_adjust,
-                common.sourcemap,
-            );
-            #[cfg(feature = "minify")]
-            let svg = minify_svg(&svg).expect("compress error");
-            svg
-        }
-        Format::Html => to_html(
-            &ansi_output,
-            theme,
-            common.width,
-            base64,
-            common.mode,
-            common.light_bg,
-            common.dark_bg,
-            common.font_size,
-            common.sourcemap,
-        ),
-        Format::Text => to_text(&ansi_output, common.width),
-        Format::Ans => to_ans(&ansi_output, common.width),
-    };
+    let output = render(&ansi_output, format, theme, base64, &common)?;
+    write_output(&output, common.output, common.open)
+}
 
-    write_output(&output, common.output, common.open);
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::from(e.exit_code())
+        }
+    }
 }
 
-fn main() {
+fn run() -> Result<(), CliError> {
     let args: Args = Args::parse();
+    check_stream(&args);
 
     // Handle subcommands
     if let Some(Commands::Cmd {
@@ -260,13 +306,9 @@ fn main() {
         let shell = shell.unwrap_or_else(|| which_shell().map(|i| i.shell).unwrap_or(Shell::Bash));
 
         if shell == Shell::Unknown {
-            eprintln!(
-                "Error: Unknown shell detected. Please specify a shell using --shell option."
-            );
-            std::process::exit(1);
+            return Err(CliError::UnknownShell);
         }
-        handle_cmd_subcommand(commands, prompt, shell, common);
-        return;
+        return handle_cmd_subcommand(commands, prompt, shell, common);
     }
 
     // Original functionality
@@ -274,81 +316,220 @@ fn main() {
     let format = common.format.unwrap_or(Format::Svg);
     let theme = common.theme.unwrap_or(Theme::Vscode);
 
-    let buf = if let Some(file) = common.input {
-        std::fs::read(file).expect("can't read string from file")
+    let mut reader: Box<dyn Read> = if let Some(file) = &common.input {
+        Box::new(File::open(file).map_err(CliError::Input)?)
     } else {
-        let mut v = Vec::new();
-        std::io::stdin()
-            .read_to_end(&mut v)
-            .expect("can't read string from stdin");
-        v
+        Box::new(std::io::stdin())
     };
 
+    // enough to sniff the image type
+    let mut buf = Vec::new();
+    reader
+        .by_ref()
+        .take(CHUNK_SIZE as u64)
+        .read_to_end(&mut buf)
+        .map_err(CliError::Input)?;
+
+    let is_image =
+        infer::get(&buf).is_some_and(|ty| ty.matcher_type() == infer::MatcherType::Image);
+    // an image is converted whole
+    if common.stream && !is_image {
+        let font = common.font.clone().map(font_to_base64).transpose()?;
+        return stream_output(buf, reader, format, theme, font, common);
+    }
+
+    reader.read_to_end(&mut buf).map_err(CliError::Input)?;
+
     let s = process_input(buf);
-    let base64 = common.font.map(|font_url| {
-        if font_url.starts_with("http") {
-            return font_url;
-        }
+    let base64 = common.font.clone().map(font_to_base64).transpose()?;
 
-        if !Path::new(&font_url).exists() {
-            return font_url;
-        }
+    let output = render(&s, format, theme, base64, &common)?;
+    write_output(&output, common.output, common.open)
+}
+
+/// --stream only works where rows can be written before the input ends, which
+/// clap can't check for the format and the subcommand
+fn check_stream(args: &Args) {
+    let common = match &args.command {
+        Some(Commands::Cmd { common, .. }) => common,
+        None => &args.common,
+    };
+    if !common.stream {
+        return;
+    }
+    let msg = match (&args.command, common.format.unwrap_or(Format::Svg)) {
+        (Some(_), _) => "--stream can't be used with the cmd subcommand",
+        (None, Format::Text | Format::Html) => return,
+        (None, _) => "--stream only supports the text and html formats",
+    };
+    Args::command()
+        .error(ErrorKind::ArgumentConflict, msg)
+        .exit()
+}
 
-        let bin = read(font_url).expect("read font file error");
-        let base64 = BASE64_STANDARD.encode(bin);
-        format!("data:font;base64,{base64}")
-    });
+/// Draw the input on a canvas, or a screen if --height is given, and render it
+fn render(
+    s: &str,
+    format: Format,
+    theme: Theme,
+    font: Option<String>,
+    common: &CommonOptions,
+) -> Result<String, CliError> {
+    let rows = common.height.filter(|&h| h > 0);
+    let canvas = Canvas::try_new_with_tabs(s, common.width, rows.unwrap_or(0), common.tab_width)
+        .map_err(CliError::Render)?;
+    // empty if the input never switched to the alternate screen
+    let canvas = if common.alternate {
+        canvas.alternate.map(|c| *c).unwrap_or_default()
+    } else {
+        canvas
+    };
+    let canvas = match (rows, common.lines) {
+        (None, _) => canvas,
+        (Some(_), None) => canvas.view(View::Screen),
+        (Some(_), Some(0)) => canvas.view(View::All),
+        (Some(_), Some(n)) => canvas.view(View::Last(n)),
+    };
 
     let output = match format {
         Format::Svg => {
-            let svg = to_svg(
-                s,
+            let svg = canvas_to_svg(
+                &canvas,
                 theme,
-                common.width,
-                base64,
+                font,
                 common.mode,
-                common.light_bg,
-                common.dark_bg,
+                common.light_bg.clone(),
+                common.dark_bg.clone(),
                 common.font_size,
-                common.length_adjust,
+                common.length_adjust.clone(),
                 common.sourcemap,
             );
             #[cfg(feature = "minify")]
-            let svg = minify_svg(&svg).expect("compress error");
+            let svg = minify_svg(&svg).map_err(CliError::Minify)?;
             svg
         }
-        Format::Html => to_html(
-            &s,
+        Format::Html => canvas_to_html(
+            &canvas,
             theme,
-            common.width,
-            base64,
+            font,
             common.mode,
-            common.light_bg,
-            common.dark_bg,
+            common.light_bg.clone(),
+            common.dark_bg.clone(),
             common.font_size,
             common.sourcemap,
         ),
-        Format::Text => to_text(&s, common.width),
-        Format::Ans => to_ans(&s, common.width),
+        Format::Text => canvas_to_text(&canvas),
+        Format::Ans => canvas_to_ans(&canvas),
+    };
+    Ok(output)
+}
+
+/// Convert a local font file to a base64 data url
+fn font_to_base64(font_url: String) -> Result<String, CliError> {
+    if font_url.starts_with("http") {
+        return Ok(font_url);
+    }
+
+    if !Path::new(&font_url).exists() {
+        return Ok(font_url);
+    }
+
+    let bin = read(font_url).map_err(CliError::Font)?;
+    let base64 = BASE64_STANDARD.encode(bin);
+    Ok(format!("data:font;base64,{base64}"))
+}
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Convert the input chunk by chunk, writing rows as soon as the cursor leaves
+/// them, so the output can differ from [`render`]'s
+fn stream_output(
+    head: Vec<u8>,
+    mut reader: impl Read,
+    format: Format,
+    theme: Theme,
+    font: Option<String>,
+    common: CommonOptions,
+) -> Result<(), CliError> {
+    let mut out: Box<dyn Write> = if let Some(path) = &common.output {
+        Box::new(BufWriter::new(
+            File::create(path).map_err(CliError::Output)?,
+        ))
+    } else {
+        Box::new(BufWriter::new(std::io::stdout().lock()))
+    };
+
+    let mut text = TextStream::new();
+    let mut html = HtmlStream::new(
+        theme,
+        font,
+        common.mode,
+        common.light_bg,
+        common.dark_bg,
+        common.font_size,
+        common.sourcemap,
+    );
+
+    if let Format::Html = format {
+        out.write_all(html.head().as_bytes())
+            .map_err(CliError::Output)?;
+    }
+
+    let mut write_rows = |out: &mut dyn Write, rows: &Canvas| {
+        let s = match format {
+            Format::Html => html.write_canvas(rows),
+            _ => text.write_rows(&rows.pixels),
+        };
+        out.write_all(s.as_bytes()).map_err(CliError::Output)
     };
 
-    write_output(&output, common.output, common.open);
+    let mut canvas = CanvasStream::new_with_tabs(common.width, common.tab_width);
+    let mut buf = head;
+    loop {
+        canvas.feed(&buf).map_err(CliError::Render)?;
+        write_rows(&mut out, &canvas.take())?;
+
+        buf.resize(CHUNK_SIZE, 0);
+        let n = reader.read(&mut buf).map_err(CliError::Input)?;
+        if n == 0 {
+            break;
+        }
+        buf.truncate(n);
+    }
+    let rest = canvas.finish().map_err(CliError::Render)?;
+    write_rows(&mut out, &rest)?;
+
+    if let Format::Html = format {
+        out.write_all(html.finish(&rest.palette).as_bytes())
+            .map_err(CliError::Output)?;
+    }
+    out.flush().map_err(CliError::Output)?;
+    drop(out);
+
+    if let Some(path) = common.output
+        && common.open
+    {
+        opener::open(&path).map_err(CliError::Open)?;
+    }
+    Ok(())
 }
 
-fn write_output(content: &str, output_path: Option<PathBuf>, open: bool) {
+fn write_output(content: &str, output_path: Option<PathBuf>, open: bool) -> Result<(), CliError> {
     if let Some(path) = output_path {
         // Write to file
-        std::fs::write(&path, content).expect("Failed to write output file");
+        std::fs::write(&path, content).map_err(CliError::Output)?;
 
         // Open in browser if requested
-        if open && let Err(e) = opener::open(&path) {
-            eprintln!("Failed to open file in browser: {}", e);
-            std::process::exit(1);
+        if open {
+            opener::open(&path).map_err(CliError::Open)?;
         }
     } else {
         // Print to stdout
-        print!("{}", content);
+        std::io::stdout()
+            .write_all(content.as_bytes())
+            .map_err(CliError::Output)?;
     }
+    Ok(())
 }
 
 #[cfg(feature = "minify")]
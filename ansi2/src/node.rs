@@ -1,59 +1,79 @@
-use crate::color::AnsiColor;
-
-#[derive(Debug, Clone, Default)]
-pub struct Node {
-    pub bg_color: AnsiColor,
-    pub color: AnsiColor,
-    pub bold: bool,
-    pub blink: bool,
-    pub text: String,
-    pub dim: bool,
-    pub italic: bool,
-    pub underline: bool,
-    pub hide: bool,
-    pub strike: bool,
-
-    // sourcemap
-    pub bg_color_r: (usize, usize),
-    pub color_r: (usize, usize),
-    pub bold_r: (usize, usize),
-    pub blink_r: (usize, usize),
-    pub text_r: (usize, usize),
-    pub dim_r: (usize, usize),
-    pub italic_r: (usize, usize),
-    pub underline_r: (usize, usize),
-    pub hide_r: (usize, usize),
-    pub strike_r: (usize, usize),
-}
-
-// ignore sourcemap fields
-impl PartialEq for Node {
-    fn eq(&self, other: &Self) -> bool {
-        self.bg_color == other.bg_color
-            && self.color == other.color
-            && self.bold == other.bold
-            && self.blink == other.blink
-            && self.text == other.text
-            && self.dim == other.dim
-            && self.italic == other.italic
-            && self.underline == other.underline
-            && self.hide == other.hide
-            && self.strike == other.strike
-    }
-}
-
-impl Eq for Node {}
-
-impl Node {
-    pub fn same_style(&self, other: &Node) -> bool {
-        self.bg_color == other.bg_color
-            && self.color == other.color
-            && self.bold == other.bold
-            && self.blink == other.blink
-            && self.dim == other.dim
-            && self.italic == other.italic
-            && self.underline == other.underline
-            && self.hide == other.hide
-            && self.strike == other.strike
-    }
-}
+use crate::color::AnsiColor;
+
+#[derive(Debug, Clone, Default)]
+pub struct Node {
+    pub bg_color: AnsiColor,
+    pub color: AnsiColor,
+    pub bold: bool,
+    pub blink: bool,
+    pub text: String,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub conceal: bool,
+    pub strike: bool,
+    /// Colors are swapped when drawn, a default one is the default color of
+    /// the theme for the other side.
+    pub reverse: bool,
+
+    // sourcemap, char ranges of the input
+    pub bg_color_r: (usize, usize),
+    pub color_r: (usize, usize),
+    pub bold_r: (usize, usize),
+    pub blink_r: (usize, usize),
+    pub text_r: (usize, usize),
+    pub dim_r: (usize, usize),
+    pub italic_r: (usize, usize),
+    pub underline_r: (usize, usize),
+    pub conceal_r: (usize, usize),
+    pub strike_r: (usize, usize),
+    pub reverse_r: (usize, usize),
+}
+
+// ignore sourcemap fields
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool {
+        self.bg_color == other.bg_color
+            && self.color == other.color
+            && self.bold == other.bold
+            && self.blink == other.blink
+            && self.text == other.text
+            && self.dim == other.dim
+            && self.italic == other.italic
+            && self.underline == other.underline
+            && self.conceal == other.conceal
+            && self.strike == other.strike
+            && self.reverse == other.reverse
+    }
+}
+
+impl Eq for Node {}
+
+impl Node {
+    /// The foreground and background the cell is drawn with.
+    pub fn draw_colors(&self) -> (AnsiColor, AnsiColor) {
+        if self.reverse {
+            (self.bg_color, self.color)
+        } else {
+            (self.color, self.bg_color)
+        }
+    }
+
+    /// The right half of a wide char, whose text is in the cell to the left.
+    pub fn is_continuation(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    pub fn same_style(&self, other: &Node) -> bool {
+        self.bg_color == other.bg_color
+            && self.color == other.color
+            && self.bold == other.bold
+            && self.blink == other.blink
+            && self.dim == other.dim
+            && self.italic == other.italic
+            && self.underline == other.underline
+            && self.conceal == other.conceal
+            && self.strike == other.strike
+            && self.reverse == other.reverse
+    }
+}
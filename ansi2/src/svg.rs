@@ -1,7 +1,9 @@
 use crate::{
     canvas::Canvas,
     css::{CssType, DEFAULT_FONTS, Mode, NodeStyle, Style},
+    error::Error,
     theme::ColorTable,
+    width::str_width,
 };
 #[allow(clippy::too_many_arguments)]
 pub fn to_svg<S: AsRef<str>, T: ColorTable>(
@@ -15,10 +17,63 @@ pub fn to_svg<S: AsRef<str>, T: ColorTable>(
     font_size: Option<usize>,
     length_adjust: Option<String>,
     sourcemap: bool,
+) -> String {
+    let canvas = Canvas::new(str, width);
+    canvas_to_svg(
+        &canvas,
+        theme,
+        font,
+        mode,
+        light_bg,
+        dark_bg,
+        font_size,
+        length_adjust,
+        sourcemap,
+    )
+}
+
+/// Like [`to_svg`], but fails on input that would need a huge canvas.
+#[allow(clippy::too_many_arguments)]
+pub fn try_to_svg<S: AsRef<str>, T: ColorTable>(
+    str: S,
+    theme: T,
+    width: Option<usize>,
+    font: Option<String>,
+    mode: Option<Mode>,
+    light_bg: Option<String>,
+    dark_bg: Option<String>,
+    font_size: Option<usize>,
+    length_adjust: Option<String>,
+    sourcemap: bool,
+) -> Result<String, Error> {
+    let canvas = Canvas::try_new(str, width)?;
+    Ok(canvas_to_svg(
+        &canvas,
+        theme,
+        font,
+        mode,
+        light_bg,
+        dark_bg,
+        font_size,
+        length_adjust,
+        sourcemap,
+    ))
+}
+
+/// Renders a canvas, e.g. one cropped with [`Canvas::view`].
+#[allow(clippy::too_many_arguments)]
+pub fn canvas_to_svg<T: ColorTable>(
+    canvas: &Canvas,
+    theme: T,
+    font: Option<String>,
+    mode: Option<Mode>,
+    light_bg: Option<String>,
+    dark_bg: Option<String>,
+    font_size: Option<usize>,
+    length_adjust: Option<String>,
+    sourcemap: bool,
 ) -> String {
     let font_size = font_size.unwrap_or(16);
-    let s = str.as_ref();
-    let canvas = Canvas::new(s, width);
     let mut s = String::new();
     let mut cur_x = 0;
     // FIXME: for better alignment
@@ -46,26 +101,34 @@ pub fn to_svg<S: AsRef<str>, T: ColorTable>(
     for row in canvas.minify().iter() {
         for c in row.iter() {
             let mut text_class = vec![NodeStyle::Text.class_name().to_string()];
-            let str_w = fn_w * c.text.chars().count();
+            let str_w = fn_w * str_width(&c.text);
             // FIXME: baseline offset
             let text_x = cur_x;
             let text_y = cur_y + baseline_h + underline_h;
 
-            if !c.bg_color.is_default() && !c.hide {
-                let name = c.bg_color.bg_class_name();
+            let (color, bg_color) = c.draw_colors();
+            if !bg_color.is_default() || c.reverse {
+                let name = if bg_color.is_default() {
+                    style.reverse_bg = true;
+                    NodeStyle::ReverseBg.class_name().to_string()
+                } else {
+                    style.add_bg_color(bg_color);
+                    bg_color.bg_class_name()
+                };
                 let class_str = format!("class='{name}'");
                 s.push_str(&format!(
                     r#"<rect x="{cur_x}" y="{}" width="{str_w}" height="{text_h}" {class_str}/>"#,
                     cur_y + underline_h
                 ));
-                style.add_bg_color(c.bg_color);
             }
 
-            if !c.color.is_default() {
-                let name = c.color.class_name();
-                text_class.push(name);
-                style.add_color(c.color);
-            };
+            if !color.is_default() {
+                text_class.push(color.class_name());
+                style.add_color(color);
+            } else if c.reverse {
+                text_class.push(NodeStyle::ReverseColor.class_name().to_string());
+                style.reverse_color = true;
+            }
 
             let mut attr = vec![];
 
@@ -93,9 +156,9 @@ pub fn to_svg<S: AsRef<str>, T: ColorTable>(
                 text_class.push(NodeStyle::Strike.class_name().to_string());
                 style.strike = true;
             }
-            if c.hide {
-                text_class.push(NodeStyle::Hide.class_name().to_string());
-                style.hide = true;
+            if c.conceal {
+                text_class.push(NodeStyle::Conceal.class_name().to_string());
+                style.conceal = true;
             }
 
             if sourcemap {
@@ -107,8 +170,9 @@ pub fn to_svg<S: AsRef<str>, T: ColorTable>(
                 text_class.push(format!("dim:{}:{}", c.dim_r.0, c.dim_r.1));
                 text_class.push(format!("italic:{}:{}", c.italic_r.0, c.italic_r.1));
                 text_class.push(format!("underline:{}:{}", c.underline_r.0, c.underline_r.1));
-                text_class.push(format!("hide:{}:{}", c.hide_r.0, c.hide_r.1));
+                text_class.push(format!("conceal:{}:{}", c.conceal_r.0, c.conceal_r.1));
                 text_class.push(format!("strike:{}:{}", c.strike_r.0, c.strike_r.1));
+                text_class.push(format!("reverse:{}:{}", c.reverse_r.0, c.reverse_r.1));
             }
 
             let class_str = if text_class.is_empty() {
@@ -146,7 +210,7 @@ attr.join(" "),
     let svg_h = (fn_h + underline_h) * canvas.h;
 
     let style_css = style.to_css(
-        theme,
+        canvas.palette.over(theme),
         CssType::Svg,
         mode,
         light_bg,
@@ -1,15 +1,44 @@
-use crate::canvas::Canvas;
-
-pub fn to_text<S: AsRef<str>>(str: S, width: Option<usize>) -> String {
-    let s = str.as_ref();
-    let canvas = Canvas::new(s, width);
-    let mut text: Vec<String> = Vec::new();
-    for row in canvas.pixels.iter() {
-        let mut row_str = String::new();
-        for c in row.iter() {
-            row_str.push_str(&c.text)
-        }
-        text.push(row_str);
-    }
-    text.join("\n")
-}
+use crate::{canvas::Canvas, error::Error, node::Node};
+
+pub fn row_to_text(row: &[Node]) -> String {
+    row.iter().map(|c| c.text.as_str()).collect()
+}
+
+pub fn to_text<S: AsRef<str>>(str: S, width: Option<usize>) -> String {
+    canvas_to_text(&Canvas::new(str, width))
+}
+
+/// Like [`to_text`], but fails on input that would need a huge canvas.
+pub fn try_to_text<S: AsRef<str>>(str: S, width: Option<usize>) -> Result<String, Error> {
+    Ok(canvas_to_text(&Canvas::try_new(str, width)?))
+}
+
+/// Renders a canvas, e.g. one cropped with [`Canvas::view`].
+pub fn canvas_to_text(canvas: &Canvas) -> String {
+    let text: Vec<String> = canvas.pixels.iter().map(|row| row_to_text(row)).collect();
+    text.join("\n")
+}
+
+/// Writes the rows taken from a [`crate::canvas::CanvasStream`] as text.
+#[derive(Debug, Clone, Default)]
+pub struct TextStream {
+    rows: usize,
+}
+
+impl TextStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_rows(&mut self, rows: &[Vec<Node>]) -> String {
+        let mut s = String::new();
+        for row in rows {
+            if self.rows > 0 {
+                s.push('\n');
+            }
+            s.push_str(&row_to_text(row));
+            self.rows += 1;
+        }
+        s
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 #[cfg_attr(feature = "wasm", wasm_bindgen::prelude::wasm_bindgen)]
 #[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
 // https://en.wikipedia.org/wiki/ANSI_escape_code#Colors
@@ -383,6 +385,20 @@ pub trait ColorTable: Copy + Sized {
     fn bright_magenta(&self) -> (u8, u8, u8);
     fn bright_cyan(&self) -> (u8, u8, u8);
     fn bright_white(&self) -> (u8, u8, u8);
+
+    fn color256(&self, n: u8) -> (u8, u8, u8) {
+        COLOR256[n as usize]
+    }
+
+    /// The default text color, `None` is white in dark mode and black in light mode.
+    fn foreground(&self) -> Option<(u8, u8, u8)> {
+        None
+    }
+
+    /// The default background color, `None` is `dark_bg` or `light_bg`.
+    fn background(&self) -> Option<(u8, u8, u8)> {
+        None
+    }
 }
 
 impl ColorTable for Theme {
@@ -450,3 +466,114 @@ impl ColorTable for Theme {
         COLORS[self.discriminant()][15]
     }
 }
+
+/// Colors redefined by OSC 4, 10 and 11.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: BTreeMap<u8, (u8, u8, u8)>,
+    pub foreground: Option<(u8, u8, u8)>,
+    pub background: Option<(u8, u8, u8)>,
+}
+
+impl Palette {
+    /// A color table where the redefined colors take precedence over `theme`.
+    pub fn over<T: ColorTable>(&self, theme: T) -> PaletteTheme<'_, T> {
+        PaletteTheme {
+            palette: self,
+            theme,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PaletteTheme<'a, T: ColorTable> {
+    palette: &'a Palette,
+    theme: T,
+}
+
+impl<T: ColorTable> PaletteTheme<'_, T> {
+    fn color(&self, n: u8, f: impl Fn(&T) -> (u8, u8, u8)) -> (u8, u8, u8) {
+        match self.palette.colors.get(&n) {
+            Some(&c) => c,
+            None => f(&self.theme),
+        }
+    }
+}
+
+impl<T: ColorTable> ColorTable for PaletteTheme<'_, T> {
+    fn black(&self) -> (u8, u8, u8) {
+        self.color(0, |t| t.black())
+    }
+
+    fn red(&self) -> (u8, u8, u8) {
+        self.color(1, |t| t.red())
+    }
+
+    fn green(&self) -> (u8, u8, u8) {
+        self.color(2, |t| t.green())
+    }
+
+    fn yellow(&self) -> (u8, u8, u8) {
+        self.color(3, |t| t.yellow())
+    }
+
+    fn blue(&self) -> (u8, u8, u8) {
+        self.color(4, |t| t.blue())
+    }
+
+    fn magenta(&self) -> (u8, u8, u8) {
+        self.color(5, |t| t.magenta())
+    }
+
+    fn cyan(&self) -> (u8, u8, u8) {
+        self.color(6, |t| t.cyan())
+    }
+
+    fn white(&self) -> (u8, u8, u8) {
+        self.color(7, |t| t.white())
+    }
+
+    fn bright_black(&self) -> (u8, u8, u8) {
+        self.color(8, |t| t.bright_black())
+    }
+
+    fn bright_red(&self) -> (u8, u8, u8) {
+        self.color(9, |t| t.bright_red())
+    }
+
+    fn bright_green(&self) -> (u8, u8, u8) {
+        self.color(10, |t| t.bright_green())
+    }
+
+    fn bright_yellow(&self) -> (u8, u8, u8) {
+        self.color(11, |t| t.bright_yellow())
+    }
+
+    fn bright_blue(&self) -> (u8, u8, u8) {
+        self.color(12, |t| t.bright_blue())
+    }
+
+    fn bright_magenta(&self) -> (u8, u8, u8) {
+        self.color(13, |t| t.bright_magenta())
+    }
+
+    fn bright_cyan(&self) -> (u8, u8, u8) {
+        self.color(14, |t| t.bright_cyan())
+    }
+
+    fn bright_white(&self) -> (u8, u8, u8) {
+        self.color(15, |t| t.bright_white())
+    }
+
+    fn color256(&self, n: u8) -> (u8, u8, u8) {
+        self.color(n, |t| t.color256(n))
+    }
+
+    fn foreground(&self) -> Option<(u8, u8, u8)> {
+        self.palette.foreground.or(self.theme.foreground())
+    }
+
+    fn background(&self) -> Option<(u8, u8, u8)> {
+        self.palette.background.or(self.theme.background())
+    }
+}
@@ -0,0 +1,116 @@
+mod tables;
+
+use tables::{EXTEND, HANGUL_L, HANGUL_T, HANGUL_V, PICTOGRAPHIC, WIDE, ZERO_WIDTH};
+
+const ZWJ: char = '\u{200D}';
+const EMOJI_PRESENTATION: char = '\u{FE0F}';
+
+fn in_table(table: &[(u32, u32)], c: char) -> bool {
+    let c = c as u32;
+    table
+        .binary_search_by(|&(start, end)| {
+            if end < c {
+                std::cmp::Ordering::Less
+            } else if start > c {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+// the precomposed syllables, every 28th of them has no trailing consonant
+fn hangul_syllable(c: char) -> Option<bool> {
+    ('\u{AC00}'..='\u{D7A3}')
+        .contains(&c)
+        .then(|| (c as u32 - 0xAC00).is_multiple_of(28))
+}
+
+// whether a Hangul syllable goes on with `c`, rules GB6 to GB8 of UAX #29
+fn joins_hangul(last: char, c: char) -> bool {
+    let (v, t) = (in_table(HANGUL_V, c), in_table(HANGUL_T, c));
+    if in_table(HANGUL_L, last) {
+        in_table(HANGUL_L, c) || v || hangul_syllable(c).is_some()
+    } else if in_table(HANGUL_V, last) || hangul_syllable(last) == Some(true) {
+        v || t
+    } else {
+        (in_table(HANGUL_T, last) || hangul_syllable(last) == Some(false)) && t
+    }
+}
+
+/// Number of cells a char takes on its own.
+pub fn char_width(c: char) -> usize {
+    if in_table(ZERO_WIDTH, c) {
+        0
+    } else if in_table(WIDE, c) {
+        2
+    } else {
+        1
+    }
+}
+
+/// Whether `c` belongs to the grapheme cluster `cluster` instead of starting
+/// a new one, a simplified version of the rules of UAX #29.
+pub fn extends(cluster: &str, c: char) -> bool {
+    let mut chars = cluster.chars();
+    let (Some(first), Some(last)) = (chars.next(), cluster.chars().next_back()) else {
+        return false;
+    };
+    if first.is_control() || c.is_control() {
+        return false;
+    }
+    // a zero width char has no cell of its own to go to
+    if in_table(EXTEND, c) || char_width(c) == 0 {
+        return true;
+    }
+    if joins_hangul(last, c) {
+        return true;
+    }
+    // a family or profession emoji
+    if last == ZWJ && in_table(PICTOGRAPHIC, c) {
+        return true;
+    }
+    // a flag is a pair of regional indicators
+    is_regional_indicator(c) && is_regional_indicator(first) && chars.next().is_none()
+}
+
+/// Splits `s` into grapheme clusters, each of them takes one logical cell.
+pub fn graphemes(s: &str) -> impl Iterator<Item = &str> {
+    let mut rest = s;
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let mut end = first.len_utf8();
+        for c in rest[end..].chars() {
+            if !extends(&rest[..end], c) {
+                break;
+            }
+            end += c.len_utf8();
+        }
+        let (cluster, tail) = rest.split_at(end);
+        rest = tail;
+        Some(cluster)
+    })
+}
+
+/// Number of cells a grapheme cluster takes.
+pub fn cluster_width(cluster: &str) -> usize {
+    let Some(first) = cluster.chars().next() else {
+        return 0;
+    };
+    let flag = is_regional_indicator(first) && cluster.chars().count() == 2;
+    if flag || cluster.contains(EMOJI_PRESENTATION) {
+        return 2;
+    }
+    char_width(first)
+}
+
+/// Number of cells a string takes, the text of a wide cell is followed by
+/// an empty continuation cell so this is also the width of a row.
+pub fn str_width(s: &str) -> usize {
+    graphemes(s).map(cluster_width).sum()
+}